@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+
+use anyhow::{Result, bail};
+use rustbus_service::rustbus::{
+    params::{Param, Variant},
+    wire::unmarshal::traits::Variant as UnVariant,
+};
+use serde::Deserialize;
+
+use crate::color::Color;
+
+/// A color that is displayed while a matching window is focused or fullscreen
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AppRule {
+    pub name: String,
+    /// Matches windows with exactly this app_id
+    pub app_id: Option<String>,
+    /// Matches windows whose title contains this string
+    pub title: Option<String>,
+    /// Only match fullscreen windows
+    #[serde(default)]
+    pub fullscreen: bool,
+    #[serde(flatten)]
+    pub color: RuleColor,
+}
+
+/// The color of a rule, missing fields are neutral
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RuleColor {
+    pub temperature: u16,
+    pub gamma: f64,
+    pub brightness: f64,
+    pub inverted: bool,
+}
+
+impl Default for RuleColor {
+    fn default() -> Self {
+        let color = Color::default();
+        Self {
+            temperature: color.temp,
+            gamma: color.gamma,
+            brightness: color.brightness,
+            inverted: color.inverted,
+        }
+    }
+}
+
+impl RuleColor {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.gamma.is_finite() || !self.brightness.is_finite() {
+            return Err("gamma and brightness must be finite".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// Not clamped, the limits are applied to the displayed color like to any other
+impl From<RuleColor> for Color {
+    fn from(color: RuleColor) -> Self {
+        Self {
+            temp: color.temperature,
+            gamma: color.gamma,
+            brightness: color.brightness,
+            inverted: color.inverted,
+        }
+    }
+}
+
+/// A window, as reported by the backend
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Toplevel {
+    pub app_id: String,
+    pub title: String,
+    pub activated: bool,
+    pub fullscreen: bool,
+}
+
+impl AppRule {
+    /// Returns `true` if the rule applies to this window. Only focused and fullscreen windows
+    /// are considered.
+    pub fn matches(&self, toplevel: &Toplevel) -> bool {
+        (toplevel.activated || toplevel.fullscreen)
+            && (!self.fullscreen || toplevel.fullscreen)
+            && self
+                .app_id
+                .as_ref()
+                .is_none_or(|app_id| *app_id == toplevel.app_id)
+            && self
+                .title
+                .as_ref()
+                .is_none_or(|title| toplevel.title.contains(title.as_str()))
+    }
+
+    /// Creates a rule from a DBus dictionary
+    pub fn from_dict(name: &str, dict: &HashMap<&str, UnVariant>) -> Result<Self> {
+        let mut rule = Self {
+            name: name.to_owned(),
+            app_id: None,
+            title: None,
+            fullscreen: false,
+            color: RuleColor::default(),
+        };
+        for (&key, value) in dict {
+            match key {
+                "app_id" => rule.app_id = Some(value.get::<String>()?),
+                "title" => rule.title = Some(value.get::<String>()?),
+                "fullscreen" => rule.fullscreen = value.get()?,
+                "temperature" => rule.color.temperature = value.get()?,
+                "gamma" => rule.color.gamma = value.get()?,
+                "brightness" => rule.color.brightness = value.get()?,
+                "inverted" => rule.color.inverted = value.get()?,
+                _ => bail!("unknown app rule field {key:?}"),
+            }
+        }
+        if rule.app_id.is_none() && rule.title.is_none() {
+            bail!("an app rule needs an app_id or a title");
+        }
+        if let Err(e) = rule.color.validate() {
+            bail!("{e}");
+        }
+        Ok(rule)
+    }
+
+    /// Returns the rule as a DBus dictionary
+    pub fn to_dict(&self) -> HashMap<&'static str, Variant<'static, 'static>> {
+        let mut dict = HashMap::new();
+        let mut insert = |key, value: Param<'static, 'static>| {
+            dict.insert(
+                key,
+                Variant {
+                    sig: value.sig(),
+                    value,
+                },
+            );
+        };
+        if let Some(app_id) = &self.app_id {
+            insert("app_id", app_id.clone().into());
+        }
+        if let Some(title) = &self.title {
+            insert("title", title.clone().into());
+        }
+        insert("fullscreen", self.fullscreen.into());
+        insert("temperature", self.color.temperature.into());
+        insert("gamma", self.color.gamma.into());
+        insert("brightness", self.color.brightness.into());
+        insert("inverted", self.color.inverted.into());
+        dict
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustbus_service::rustbus::MessageBuilder;
+    use rustbus_service::rustbus::message_builder::MarshalledMessage;
+
+    use super::*;
+
+    fn rule(app_id: Option<&str>, title: Option<&str>) -> AppRule {
+        AppRule {
+            name: "rule".to_owned(),
+            app_id: app_id.map(str::to_owned),
+            title: title.map(str::to_owned),
+            fullscreen: false,
+            color: RuleColor::default(),
+        }
+    }
+
+    fn focused(app_id: &str, title: &str) -> Toplevel {
+        Toplevel {
+            app_id: app_id.to_owned(),
+            title: title.to_owned(),
+            activated: true,
+            fullscreen: false,
+        }
+    }
+
+    /// `UnVariant`s can only be parsed from a message, so the dictionary is marshalled like a
+    /// client would
+    fn dict_msg(entries: Vec<(&str, Param)>) -> MarshalledMessage {
+        let dict = entries
+            .into_iter()
+            .map(|(key, value)| {
+                let variant = Variant {
+                    sig: value.sig(),
+                    value,
+                };
+                (key, variant)
+            })
+            .collect::<HashMap<_, _>>();
+        let mut msg = MessageBuilder::new()
+            .call("SetAppRule")
+            .on("/")
+            .with_interface("rs.wl.gammarelay")
+            .build();
+        msg.body.push_param(dict).unwrap();
+        msg
+    }
+
+    fn parse(msg: &MarshalledMessage) -> Result<AppRule> {
+        let dict = msg.body.parser().get::<HashMap<&str, UnVariant>>().unwrap();
+        AppRule::from_dict("rule", &dict)
+    }
+
+    #[test]
+    fn matches_app_id_and_title() {
+        let window = focused("mpv", "Movie - mpv");
+        assert!(rule(Some("mpv"), None).matches(&window));
+        assert!(rule(None, Some("Movie")).matches(&window));
+        assert!(rule(Some("mpv"), Some("Movie")).matches(&window));
+        assert!(!rule(Some("mp"), None).matches(&window));
+        assert!(!rule(Some("mpv"), Some("Film")).matches(&window));
+    }
+
+    #[test]
+    fn matches_only_focused_or_fullscreen() {
+        let mut window = focused("mpv", "");
+        window.activated = false;
+        assert!(!rule(Some("mpv"), None).matches(&window));
+        window.fullscreen = true;
+        assert!(rule(Some("mpv"), None).matches(&window));
+
+        let mut fullscreen_rule = rule(Some("mpv"), None);
+        fullscreen_rule.fullscreen = true;
+        assert!(!fullscreen_rule.matches(&focused("mpv", "")));
+        assert!(fullscreen_rule.matches(&window));
+    }
+
+    #[test]
+    fn from_dict() {
+        let msg = dict_msg(vec![
+            ("app_id", "mpv".to_owned().into()),
+            ("brightness", 0.5f64.into()),
+        ]);
+        let parsed = parse(&msg).unwrap();
+        assert_eq!(parsed.app_id.as_deref(), Some("mpv"));
+        assert_eq!(parsed.title, None);
+        assert_eq!(
+            parsed.color,
+            RuleColor {
+                brightness: 0.5,
+                ..RuleColor::default()
+            }
+        );
+
+        let msg = dict_msg(vec![("title", "Movie".to_owned().into())]);
+        assert_eq!(parse(&msg).unwrap().title.as_deref(), Some("Movie"));
+    }
+
+    #[test]
+    fn from_dict_rejects_invalid_rules() {
+        let invalid = [
+            vec![("brightness", 0.5f64.into())],
+            vec![("app_id", "mpv".to_owned().into()), ("color", 1u32.into())],
+            vec![("app_id", "mpv".to_owned().into()), ("gamma", 1u32.into())],
+            vec![
+                ("app_id", "mpv".to_owned().into()),
+                ("brightness", f64::NAN.into()),
+            ],
+        ];
+        for entries in invalid {
+            assert!(parse(&dict_msg(entries)).is_err());
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use std::os::fd::AsRawFd;
+
+use anyhow::Result;
+
+use crate::state::State;
+
+/// Something that can display gamma ramps
+///
+/// A backend owns the [`State`]: it adds and removes outputs as they appear, and uploads a new
+/// ramp whenever the color of an output changes.
+pub trait Backend: AsRawFd {
+    fn state(&self) -> &State;
+
+    fn state_mut(&mut self) -> &mut State;
+
+    /// Processes pending events and displays the colors of all changed outputs
+    fn poll(&mut self) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum BackendKind {
+    /// X11 if `WAYLAND_DISPLAY` is unset and `DISPLAY` is set, Wayland otherwise
+    #[default]
+    Auto,
+    /// wlr-gamma-control-unstable-v1
+    Wayland,
+    /// RandR CRTC gamma
+    #[cfg(feature = "x11")]
+    X11,
+    /// Fake outputs, for testing
+    Headless,
+}
+
+impl BackendKind {
+    /// Resolves [`BackendKind::Auto`] based on the environment
+    pub fn resolve(self) -> Self {
+        match self {
+            #[cfg(feature = "x11")]
+            Self::Auto
+                if std::env::var_os("WAYLAND_DISPLAY").is_none()
+                    && std::env::var_os("DISPLAY").is_some() =>
+            {
+                Self::X11
+            }
+            Self::Auto => Self::Wayland,
+            other => other,
+        }
+    }
+}
@@ -1,5 +1,7 @@
+use serde::{Deserialize, Serialize};
+
 /// Color parameters
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Color {
     pub temp: u16,
     pub gamma: f64,
@@ -18,15 +20,173 @@ impl Default for Color {
     }
 }
 
+/// Some of the color parameters, as passed to `SetColor`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ColorFields {
+    pub temp: Option<u16>,
+    pub gamma: Option<f64>,
+    pub brightness: Option<f64>,
+    pub inverted: Option<bool>,
+}
+
+impl ColorFields {
+    pub fn validate(&self) -> Result<(), String> {
+        let numbers = [self.gamma, self.brightness];
+        if numbers
+            .into_iter()
+            .flatten()
+            .any(|value| !value.is_finite())
+        {
+            return Err("values must be finite".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// Relative changes of the color parameters, as passed to `UpdateColor`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ColorDeltas {
+    pub temp: i16,
+    pub gamma: f64,
+    pub brightness: f64,
+    pub toggle_inverted: bool,
+}
+
+/// Per-output adjustments applied on top of the color of an output, so that outputs keep their
+/// differences when they are all set to the same color
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Calibration {
+    pub temp_offset: i16,
+    pub gamma_scale: f64,
+    pub brightness_scale: f64,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self {
+            temp_offset: 0,
+            gamma_scale: 1.0,
+            brightness_scale: 1.0,
+        }
+    }
+}
+
+impl Calibration {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.gamma_scale.is_finite() || self.gamma_scale <= 0.0 {
+            return Err("the gamma scale must be positive".to_owned());
+        }
+        if !self.brightness_scale.is_finite() || self.brightness_scale < 0.0 {
+            return Err("the brightness scale must not be negative".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// The allowed range of each color parameter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub min_temp: u16,
+    pub max_temp: u16,
+    pub min_gamma: f64,
+    pub max_gamma: f64,
+    pub min_brightness: f64,
+    pub max_brightness: f64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            min_temp: 1_000,
+            max_temp: 10_000,
+            min_gamma: 0.1,
+            max_gamma: f64::INFINITY,
+            min_brightness: 0.0,
+            max_brightness: 1.0,
+        }
+    }
+}
+
+impl Limits {
+    /// Checks that the limits are within the range that can be displayed
+    pub fn validate(&self) -> Result<(), String> {
+        let default = Self::default();
+        if self.min_temp < default.min_temp || self.max_temp > default.max_temp {
+            return Err(format!(
+                "temperature limits must be within [{}, {}]",
+                default.min_temp, default.max_temp
+            ));
+        }
+        let floats = [
+            self.min_gamma,
+            self.max_gamma,
+            self.min_brightness,
+            self.max_brightness,
+        ];
+        if floats.iter().any(|f| f.is_nan()) {
+            return Err("limits must be numbers".to_owned());
+        }
+        if self.min_gamma <= 0.0 {
+            return Err("the minimum gamma must be positive".to_owned());
+        }
+        if self.min_brightness < 0.0 || self.max_brightness > 1.0 {
+            return Err("brightness limits must be within [0, 1]".to_owned());
+        }
+        if self.min_temp > self.max_temp
+            || self.min_gamma > self.max_gamma
+            || self.min_brightness > self.max_brightness
+        {
+            return Err("a minimum is greater than the corresponding maximum".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Clamps every parameter of `color` to its range
+    pub fn clamp(&self, color: Color) -> Color {
+        Color {
+            temp: color.temp.clamp(self.min_temp, self.max_temp),
+            gamma: color.gamma.clamp(self.min_gamma, self.max_gamma),
+            brightness: color
+                .brightness
+                .clamp(self.min_brightness, self.max_brightness),
+            inverted: color.inverted,
+        }
+    }
+}
+
 impl Color {
-    /// Returns a color with update temperature, clamping it to [1K, 10K] range, or none if
-    /// temperature could not be updated.
-    pub fn with_updated_temp(self, delta: i16) -> Option<Self> {
-        let new_temp = self.temp.saturating_add_signed(delta).clamp(1_000, 10_000);
-        (new_temp != self.temp).then_some(Self {
-            temp: new_temp,
-            ..self
-        })
+    /// Returns a color with the given fields replaced. The result has to be clamped with
+    /// [`Limits::clamp`].
+    pub fn with_fields(self, fields: ColorFields) -> Self {
+        Self {
+            temp: fields.temp.unwrap_or(self.temp),
+            gamma: fields.gamma.unwrap_or(self.gamma),
+            brightness: fields.brightness.unwrap_or(self.brightness),
+            inverted: fields.inverted.unwrap_or(self.inverted),
+        }
+    }
+
+    /// Returns a color with the given deltas applied. The result has to be clamped with
+    /// [`Limits::clamp`].
+    pub fn with_deltas(self, deltas: ColorDeltas) -> Self {
+        Self {
+            temp: self.temp.saturating_add_signed(deltas.temp),
+            gamma: self.gamma + deltas.gamma,
+            brightness: self.brightness + deltas.brightness,
+            inverted: self.inverted != deltas.toggle_inverted,
+        }
+    }
+
+    /// Returns the color with a calibration applied. The result has to be clamped with
+    /// [`Limits::clamp`].
+    pub fn with_calibration(self, calibration: Calibration) -> Self {
+        Self {
+            temp: self.temp.saturating_add_signed(calibration.temp_offset),
+            gamma: self.gamma * calibration.gamma_scale,
+            brightness: self.brightness * calibration.brightness_scale,
+            inverted: self.inverted,
+        }
     }
 }
 
@@ -0,0 +1,111 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::app_rules::AppRule;
+use crate::state::Aggregation;
+
+/// The configuration file, `$XDG_CONFIG_HOME/wl-gammarelay-rs/config.toml` by default
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub idle_dim_timeout: Option<u32>,
+    pub idle_dim_brightness: Option<f64>,
+    pub min_temperature: Option<u16>,
+    pub max_temperature: Option<u16>,
+    pub min_gamma: Option<f64>,
+    pub max_gamma: Option<f64>,
+    pub min_brightness: Option<f64>,
+    pub max_brightness: Option<f64>,
+    pub root_aggregation: Option<Aggregation>,
+    pub primary_output: Option<String>,
+    pub read_only: bool,
+    pub allow_writers: Vec<String>,
+    #[serde(rename = "app_rule")]
+    pub app_rules: Vec<AppRule>,
+}
+
+impl Config {
+    /// Loads the config from `path`, or from the default location. A missing default config is
+    /// not an error.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_owned(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Self::default()),
+            },
+        };
+
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default());
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("could not read {}", path.display()));
+            }
+        };
+        let config: Self =
+            toml::from_str(&text).with_context(|| format!("invalid config {}", path.display()))?;
+        for rule in &config.app_rules {
+            rule.color.validate().map_err(|e| {
+                anyhow::anyhow!(
+                    "invalid app rule {:?} in {}: {e}",
+                    rule.name,
+                    path.display()
+                )
+            })?;
+        }
+        Ok(config)
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    Some(xdg_dir("XDG_CONFIG_HOME", ".config")?.join("config.toml"))
+}
+
+/// Returns the directory for persistent state, `$XDG_DATA_HOME/wl-gammarelay-rs`
+pub fn data_dir() -> Option<PathBuf> {
+    xdg_dir("XDG_DATA_HOME", ".local/share")
+}
+
+/// Reads a TOML file from the data directory, or returns the default if it does not exist
+pub fn load_data<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => {
+            toml::from_str(&text).with_context(|| format!("invalid data file {}", path.display()))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e).with_context(|| format!("could not read {}", path.display())),
+    }
+}
+
+/// Writes a TOML file to the data directory, creating the directory if needed
+pub fn save_data<T: Serialize>(path: &Path, data: &T) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("could not create {}", dir.display()))?;
+    }
+    std::fs::write(path, toml::to_string(data)?)
+        .with_context(|| format!("could not write {}", path.display()))
+}
+
+/// Returns the directory where the session bus looks for activatable services,
+/// `$XDG_DATA_HOME/dbus-1/services`
+pub fn dbus_services_dir() -> Option<PathBuf> {
+    Some(xdg_base("XDG_DATA_HOME", ".local/share")?.join("dbus-1/services"))
+}
+
+fn xdg_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    Some(xdg_base(var, fallback)?.join("wl-gammarelay-rs"))
+}
+
+fn xdg_base(var: &str, fallback: &str) -> Option<PathBuf> {
+    std::env::var_os(var)
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(fallback)))
+}
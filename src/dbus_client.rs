@@ -25,14 +25,14 @@ impl AsRawFd for DbusClient {
 }
 
 impl DbusClient {
-    pub fn new(format: String, server_running: bool) -> Result<Self> {
+    pub fn new(format: String, bus_name: &str, server_running: bool) -> Result<Self> {
         let mut conn = DuplexConn::connect_to_bus(get_session_bus_path()?, true)?;
         conn.send_hello(Timeout::Infinite)?;
 
         conn.send
-            .send_message_write_all(&standard_messages::add_match(
-                "type='signal',sender='rs.wl-gammarelay',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged'",
-            ))?;
+            .send_message_write_all(&standard_messages::add_match(&format!(
+                "type='signal',sender='{bus_name}',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged'",
+            )))?;
 
         let mut temperature = 6500;
         let mut gamma = 1.0;
@@ -46,7 +46,7 @@ impl DbusClient {
                 .call("Get")
                 .on("/")
                 .with_interface("org.freedesktop.DBus.Properties")
-                .at("rs.wl-gammarelay")
+                .at(bus_name)
                 .build();
 
             msg.body.reset();
@@ -113,8 +113,6 @@ impl DbusClient {
                 let iface = parser.get::<&str>()?;
                 if iface == "rs.wl.gammarelay" {
                     let changed = parser.get::<HashMap<&str, UnVariant>>()?;
-                    let invalidated = parser.get::<Vec<&str>>()?;
-                    assert!(invalidated.is_empty());
                     if let Some(v) = changed.get("Temperature") {
                         self.temperature = v.get::<u16>()?;
                     }
@@ -1,25 +1,57 @@
+mod gnome;
+mod kde;
+mod policy;
+
 use std::collections::HashMap;
 use std::os::fd::{AsRawFd, RawFd};
+use std::time::{Duration, SystemTime};
 
 use anyhow::Result;
 use rustbus::{
-    DuplexConn, MessageBuilder,
+    DuplexConn, MessageBuilder, MessageType,
     connection::Timeout,
     get_session_bus_path,
     message_builder::MarshalledMessage,
     params::{Param, Variant},
+    wire::ObjectPath,
     wire::unmarshal::traits::Variant as UnVariant,
 };
-use rustbus_service::{Access, InterfaceImp, MethodContext, PropContext, Service, rustbus};
+use rustbus_service::{
+    Access, InterfaceImp, MethodContext, MethodError, PropContext, Service, rustbus,
+};
 
-use crate::color::Color;
-use crate::wayland::WaylandState;
+use crate::app_rules::AppRule;
+use crate::color::{Calibration, Color, ColorDeltas, ColorFields};
+use crate::history::HistoryEntry;
+use crate::name_watcher::NameWatcher;
+use crate::presets::Preset;
+use crate::state::{Aggregation, Output, State, Target, object_name};
+
+pub use policy::WritePolicy;
 
 pub struct DbusServer {
     conn: DuplexConn,
-    service: Service<WaylandState>,
+    service: Service<State>,
+    name_watcher: NameWatcher,
+    /// The well-known name of the service
+    bus_name: String,
+    /// The unique name of the service connection
+    unique_name: String,
+    /// Set when another instance took over the bus name
+    name_lost: bool,
+    /// The colors of the root and the groups as of the last signal, used to detect changes
+    /// caused by hotplug
+    aggregate_colors: Vec<(String, Color)>,
+    active_preset: String,
+    uniform: bool,
+    gnome: CompatSnapshot,
+    kde: CompatSnapshot,
 }
 
+const OBJECT_MANAGER_IFACE: &str = "org.freedesktop.DBus.ObjectManager";
+/// How long `run --replace` waits for the previous instance to exit
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(5);
+
 impl AsRawFd for DbusServer {
     fn as_raw_fd(&self) -> RawFd {
         self.conn.as_raw_fd()
@@ -27,36 +59,66 @@ impl AsRawFd for DbusServer {
 }
 
 impl DbusServer {
-    pub fn new() -> Result<Option<Self>> {
+    /// Returns `None` if another instance owns `bus_name`. With `replace`, the other instance
+    /// is asked to exit instead, if it allows it.
+    pub fn new(bus_name: &str, replace: bool) -> Result<Option<Self>> {
         let mut conn = rustbus::DuplexConn::connect_to_bus(get_session_bus_path()?, true)?;
-        conn.send_hello(Timeout::Infinite)?;
+        let unique_name = conn.send_hello(Timeout::Infinite)?;
 
         let mut service = Service::new();
 
         // See https://github.com/MaxVerevkin/wl-gammarelay-rs/issues/22
         service.set_error_cb("org.freedesktop.DBus.Error.ServiceUnknown", |_| ());
 
-        let req_name_serial =
-            conn.send
-                .send_message_write_all(&rustbus::standard_messages::request_name(
-                    "rs.wl-gammarelay",
-                    0,
-                ))?;
-        let req_name_resp = service.get_reply(&mut conn, req_name_serial, Timeout::Infinite)?;
-        if req_name_resp.body.parser().get::<u32>()?
-            != rustbus::standard_messages::DBUS_REQUEST_NAME_REPLY_PRIMARY_OWNER
-        {
+        // Watch before requesting the name, so that the previous owner leaving is not missed
+        let mut name_watcher = NameWatcher::new(bus_name)?;
+        let previous_owner = name_owner(&mut conn, &mut service, bus_name)?;
+        if !request_name(&mut conn, &mut service, bus_name, replace)? {
             return Ok(None);
         }
+        // The previous owner has to release its gamma controls before new ones can be created
+        if let Some(previous_owner) = previous_owner {
+            eprintln!("Replacing {previous_owner}");
+            if !name_watcher.wait_until_vanished(&previous_owner, REPLACE_TIMEOUT)? {
+                eprintln!("{previous_owner} did not exit");
+            }
+        }
+        // The compatibility names are optional, they are taken inside GNOME and Plasma. They are
+        // never replaced, only released by a previous instance when it exits.
+        for name in [gnome::BUS_NAME, kde::BUS_NAME] {
+            if !request_name(&mut conn, &mut service, name, false)? {
+                eprintln!("Could not acquire {name}");
+            }
+        }
 
         let gammarelay_root_iface = InterfaceImp::new("rs.wl.gammarelay")
             .with_method::<(), ()>("ToggleInverted", toggle_inverted_root_cb)
+            .with_method::<(), ()>("ToggleEnabled", toggle_enabled_root_cb)
             .with_method::<UpdateTemperatureArgs, ()>(
                 "UpdateTemperature",
                 update_temperature_root_cb,
             )
             .with_method::<UpdateGammaArgs, ()>("UpdateGamma", update_gamma_root_cb)
             .with_method::<UpdateBrightnessArgs, ()>("UpdateBrightness", update_brightness_root_cb)
+            .with_method::<ColorArgs, ()>("SetColor", set_color_root_cb)
+            .with_method::<ColorArgs, ()>("UpdateColor", update_color_root_cb)
+            .with_method::<SetTemporaryArgs, ()>("SetTemporary", set_temporary_root_cb)
+            .with_method::<(), ()>("CancelTemporary", cancel_temporary_root_cb)
+            .with_method::<InhibitArgs, u32>("Inhibit", inhibit_cb)
+            .with_method::<UnInhibitArgs, ()>("UnInhibit", uninhibit_cb)
+            .with_method::<PresetArgs, ()>("SavePreset", save_preset_cb)
+            .with_method::<PresetArgs, ()>("ApplyPreset", apply_preset_cb)
+            .with_method::<PresetArgs, ()>("DeletePreset", delete_preset_cb)
+            .with_method::<(), Vec<String>>("ListPresets", list_presets_cb)
+            .with_method::<CyclePresetsArgs, ()>("CyclePresets", cycle_presets_cb)
+            .with_method::<SetAppRuleArgs, ()>("SetAppRule", set_app_rule_cb)
+            .with_method::<RemoveAppRuleArgs, ()>("RemoveAppRule", remove_app_rule_cb)
+            .with_method::<(), AppRuleList>("ListAppRules", list_app_rules_cb)
+            .with_method::<(), bool>("Undo", undo_cb)
+            .with_method::<(), bool>("Redo", redo_cb)
+            .with_method::<(), HistoryList>("GetHistory", get_history_cb)
+            .with_method::<CreateGroupArgs, ()>("CreateGroup", create_group_cb)
+            .with_method::<DeleteGroupArgs, ()>("DeleteGroup", delete_group_cb)
             .with_prop(
                 "Inverted",
                 Access::ReadWrite(get_inverted_root_cb, set_inverted_root_cb),
@@ -72,286 +134,877 @@ impl DbusServer {
             .with_prop(
                 "Brightness",
                 Access::ReadWrite(get_brightness_root_cb, set_brightness_root_cb),
+            )
+            .with_prop(
+                "TemporaryRemaining",
+                Access::Read(get_temporary_remaining_root_cb),
+            )
+            .with_prop(
+                "Enabled",
+                Access::ReadWrite(get_enabled_root_cb, set_enabled_root_cb),
+            )
+            .with_prop("ActivePreset", Access::Read(get_active_preset_cb))
+            .with_prop(
+                "RootAggregation",
+                Access::ReadWrite(get_root_aggregation_cb, set_root_aggregation_cb),
+            )
+            .with_prop("Uniform", Access::Read(get_uniform_cb))
+            .with_prop("Inhibited", Access::Read(get_inhibited_cb))
+            .with_prop("Inhibitors", Access::Read(get_inhibitors_cb))
+            .with_prop("MinTemperature", Access::Read(get_min_temperature_cb))
+            .with_prop("MaxTemperature", Access::Read(get_max_temperature_cb))
+            .with_prop("MinGamma", Access::Read(get_min_gamma_cb))
+            .with_prop("MaxGamma", Access::Read(get_max_gamma_cb))
+            .with_prop("MinBrightness", Access::Read(get_min_brightness_cb))
+            .with_prop("MaxBrightness", Access::Read(get_max_brightness_cb))
+            .with_prop(
+                "IdleDimTimeout",
+                Access::ReadWrite(get_idle_dim_timeout_cb, set_idle_dim_timeout_cb),
+            )
+            .with_prop(
+                "IdleDimBrightness",
+                Access::ReadWrite(get_idle_dim_brightness_cb, set_idle_dim_brightness_cb),
             );
 
+        let object_manager_iface = InterfaceImp::new(OBJECT_MANAGER_IFACE)
+            .with_method::<(), ManagedObjects>("GetManagedObjects", get_managed_objects_cb);
+
         let root = service.root_mut();
         root.add_interface(gammarelay_root_iface);
+        root.add_interface(object_manager_iface);
         root.add_child("outputs", rustbus_service::Object::new());
+        root.add_child("groups", rustbus_service::Object::new());
+        gnome::add_object(&mut service);
+        kde::add_object(&mut service);
+
+        Ok(Some(Self {
+            conn,
+            service,
+            name_watcher,
+            bus_name: bus_name.to_owned(),
+            unique_name,
+            name_lost: false,
+            aggregate_colors: Vec::new(),
+            active_preset: String::new(),
+            uniform: true,
+            gnome: CompatSnapshot::default(),
+            kde: CompatSnapshot::default(),
+        }))
+    }
 
-        Ok(Some(Self { conn, service }))
+    pub fn add_output(&mut self, state: &State, reg_name: u32, name: &str) {
+        let path = output_object_path(name);
+        self.add_color_object(&path, Target::Output(reg_name));
+        if let Some(props) = object_properties(state, Target::Output(reg_name)) {
+            self.signal_interfaces_added(&path, props);
+        }
     }
 
-    pub fn add_output(&mut self, reg_name: u32, name: &str) {
-        let toggle_inverted_output_cb = move |ctx: &mut MethodContext<WaylandState>, _args: ()| {
-            let global_color = ctx.state.color();
+    pub fn remove_output(&mut self, name: &str) {
+        self.remove_color_object(&output_object_path(name));
+    }
 
-            let output = ctx.state.mut_output_by_reg_name(reg_name).unwrap();
-            let color = output.color();
-            let inverted = !color.inverted;
-            output.set_color(Color { inverted, ..color });
+    pub fn add_group(&mut self, state: &State, id: u32) {
+        let Some(group) = state.group(id) else {
+            return;
+        };
+        let path = group.object_path();
+        self.add_color_object(&path, Target::Group(id));
+        if let Some(props) = object_properties(state, Target::Group(id)) {
+            self.signal_interfaces_added(&path, props);
+        }
+        self.aggregate_colors = aggregate_colors(state);
+    }
 
-            let value = inverted.into();
-            signal_change(ctx.conn, ctx.object_path, "Inverted", value);
+    pub fn remove_group(&mut self, path: &str) {
+        self.remove_color_object(path);
+    }
 
-            if ctx.state.color().inverted != global_color.inverted {
-                let value = inverted.into();
-                signal_change(ctx.conn, "/", "Inverted", value);
-            }
+    /// Exports the `rs.wl.gammarelay` interface of an output or a group
+    fn add_color_object(&mut self, path: &str, target: Target) {
+        let toggle_inverted_cb = move |ctx: &mut MethodContext<State>, _args: ()| {
+            check_write(ctx.state, &ctx.msg)?;
+            let deltas = ColorDeltas {
+                toggle_inverted: true,
+                ..Default::default()
+            };
+            update_deltas(ctx.conn, ctx.state, sender(&ctx.msg), target, deltas)
         };
 
-        let get_inverted_output_cb = move |ctx: PropContext<WaylandState>| {
-            ctx.state
-                .output_by_reg_name(reg_name)
-                .unwrap()
-                .color()
-                .inverted
+        let get_inverted_cb = move |ctx: PropContext<State>| {
+            Ok::<_, MethodError>(target_color(ctx.state, target)?.inverted)
         };
 
-        let set_inverted_output_cb = move |ctx: PropContext<WaylandState>, val: UnVariant| {
-            let global_color = ctx.state.color();
-
-            let output = ctx.state.mut_output_by_reg_name(reg_name).unwrap();
-            let color = output.color();
-            let inverted = val.get::<bool>().unwrap();
-
-            if color.inverted != inverted {
-                output.set_color(Color { inverted, ..color });
+        let set_inverted_cb = move |ctx: PropContext<State>, val: UnVariant| {
+            check_write(ctx.state, &ctx.msg)?;
+            let fields = ColorFields {
+                inverted: Some(prop_value(&val)?),
+                ..Default::default()
+            };
+            set_fields(ctx.conn, ctx.state, sender(&ctx.msg), target, fields)
+        };
 
-                let value = inverted.into();
-                signal_change(ctx.conn, ctx.object_path, "Inverted", value);
+        let update_brightness_cb =
+            move |ctx: &mut MethodContext<State>, args: UpdateBrightnessArgs| {
+                check_write(ctx.state, &ctx.msg)?;
+                let deltas = ColorDeltas {
+                    brightness: args.delta,
+                    ..Default::default()
+                };
+                update_deltas(ctx.conn, ctx.state, sender(&ctx.msg), target, deltas)
+            };
 
-                if ctx.state.color().inverted != global_color.inverted {
-                    let value = inverted.into();
-                    signal_change(ctx.conn, "/", "Inverted", value);
-                }
-            }
+        let get_brightness_cb = move |ctx: PropContext<State>| {
+            Ok::<_, MethodError>(target_color(ctx.state, target)?.brightness)
         };
 
-        let update_brightness_output_cb =
-            move |ctx: &mut MethodContext<WaylandState>, args: UpdateBrightnessArgs| {
-                let global_color = ctx.state.color();
+        let set_brightness_cb = move |ctx: PropContext<State>, val: UnVariant| {
+            check_write(ctx.state, &ctx.msg)?;
+            let fields = ColorFields {
+                brightness: Some(finite_prop_value(&val)?),
+                ..Default::default()
+            };
+            set_fields(ctx.conn, ctx.state, sender(&ctx.msg), target, fields)
+        };
 
-                let output = ctx.state.mut_output_by_reg_name(reg_name).unwrap();
-                let color = output.color();
-                let brightness = (color.brightness + args.delta).clamp(0.0, 1.0);
+        let update_temperature_cb =
+            move |ctx: &mut MethodContext<State>, args: UpdateTemperatureArgs| {
+                check_write(ctx.state, &ctx.msg)?;
+                let deltas = ColorDeltas {
+                    temp: args.delta,
+                    ..Default::default()
+                };
+                update_deltas(ctx.conn, ctx.state, sender(&ctx.msg), target, deltas)
+            };
 
-                if color.brightness != brightness {
-                    output.set_color(Color {
-                        brightness,
-                        ..color
-                    });
+        let get_temperature_cb = move |ctx: PropContext<State>| {
+            Ok::<_, MethodError>(target_color(ctx.state, target)?.temp)
+        };
 
-                    let value = brightness.into();
-                    signal_change(ctx.conn, ctx.object_path, "Brightness", value);
+        let set_temperature_cb = move |ctx: PropContext<State>, val: UnVariant| {
+            check_write(ctx.state, &ctx.msg)?;
+            let fields = ColorFields {
+                temp: Some(prop_value(&val)?),
+                ..Default::default()
+            };
+            set_fields(ctx.conn, ctx.state, sender(&ctx.msg), target, fields)
+        };
 
-                    let brightness = ctx.state.color().brightness;
-                    if brightness != global_color.brightness {
-                        let value = brightness.into();
-                        signal_change(ctx.conn, "/", "Brightness", value);
-                    }
-                }
+        let update_gamma_cb = move |ctx: &mut MethodContext<State>, args: UpdateGammaArgs| {
+            check_write(ctx.state, &ctx.msg)?;
+            let deltas = ColorDeltas {
+                gamma: args.delta,
+                ..Default::default()
             };
+            update_deltas(ctx.conn, ctx.state, sender(&ctx.msg), target, deltas)
+        };
 
-        let get_brightness_output_cb = move |ctx: PropContext<WaylandState>| {
-            ctx.state
-                .output_by_reg_name(reg_name)
-                .unwrap()
-                .color()
-                .brightness
+        let get_gamma_cb = move |ctx: PropContext<State>| {
+            Ok::<_, MethodError>(target_color(ctx.state, target)?.gamma)
         };
 
-        let set_brightness_output_cb = move |ctx: PropContext<WaylandState>, val: UnVariant| {
-            let global_color = ctx.state.color();
+        let set_gamma_cb = move |ctx: PropContext<State>, val: UnVariant| {
+            check_write(ctx.state, &ctx.msg)?;
+            let fields = ColorFields {
+                gamma: Some(finite_prop_value(&val)?),
+                ..Default::default()
+            };
+            set_fields(ctx.conn, ctx.state, sender(&ctx.msg), target, fields)
+        };
 
-            let output = ctx.state.mut_output_by_reg_name(reg_name).unwrap();
-            let color = output.color();
-            let brightness = val.get::<f64>().unwrap().clamp(0.0, 1.0);
+        let set_color_cb = move |ctx: &mut MethodContext<State>, args: ColorArgs| {
+            check_write(ctx.state, &ctx.msg)?;
+            let fields = color_fields(&args.color)?;
+            set_fields(ctx.conn, ctx.state, sender(&ctx.msg), target, fields)
+        };
 
-            if color.brightness != brightness {
-                output.set_color(Color {
-                    brightness,
-                    ..color
-                });
+        let update_color_cb = move |ctx: &mut MethodContext<State>, args: ColorArgs| {
+            check_write(ctx.state, &ctx.msg)?;
+            let deltas = color_deltas(&args.color)?;
+            update_deltas(ctx.conn, ctx.state, sender(&ctx.msg), target, deltas)
+        };
 
-                let value = brightness.into();
-                signal_change(ctx.conn, ctx.object_path, "Brightness", value);
+        let set_temporary_cb = move |ctx: &mut MethodContext<State>, args: SetTemporaryArgs| {
+            check_write(ctx.state, &ctx.msg)?;
+            set_temporary(ctx, target, args)
+        };
 
-                let brightness = ctx.state.color().brightness;
-                if brightness != global_color.brightness {
-                    let value = brightness.into();
-                    signal_change(ctx.conn, "/", "Brightness", value);
-                }
+        let cancel_temporary_cb = move |ctx: &mut MethodContext<State>, _args: ()| {
+            check_write(ctx.state, &ctx.msg)?;
+            target_color(ctx.state, target)?;
+            if ctx.state.cancel_temporary(target) {
+                signal_temporary(ctx.conn, ctx.state);
             }
+            Ok::<_, MethodError>(())
         };
 
-        let update_temperature_output_cb =
-            move |ctx: &mut MethodContext<WaylandState>, args: UpdateTemperatureArgs| {
-                let global_color = ctx.state.color();
+        let get_temporary_remaining_cb = move |ctx: PropContext<State>| {
+            target_color(ctx.state, target)?;
+            Ok::<_, MethodError>(temporary_remaining(ctx.state, target))
+        };
+
+        let mut iface = InterfaceImp::new("rs.wl.gammarelay")
+            .with_method::<(), ()>("ToggleInverted", toggle_inverted_cb)
+            .with_method::<UpdateTemperatureArgs, ()>("UpdateTemperature", update_temperature_cb)
+            .with_method::<UpdateGammaArgs, ()>("UpdateGamma", update_gamma_cb)
+            .with_method::<UpdateBrightnessArgs, ()>("UpdateBrightness", update_brightness_cb)
+            .with_method::<ColorArgs, ()>("SetColor", set_color_cb)
+            .with_method::<ColorArgs, ()>("UpdateColor", update_color_cb)
+            .with_method::<SetTemporaryArgs, ()>("SetTemporary", set_temporary_cb)
+            .with_method::<(), ()>("CancelTemporary", cancel_temporary_cb)
+            .with_prop(
+                "Inverted",
+                Access::ReadWrite(get_inverted_cb, set_inverted_cb),
+            )
+            .with_prop(
+                "Temperature",
+                Access::ReadWrite(get_temperature_cb, set_temperature_cb),
+            )
+            .with_prop("Gamma", Access::ReadWrite(get_gamma_cb, set_gamma_cb))
+            .with_prop(
+                "Brightness",
+                Access::ReadWrite(get_brightness_cb, set_brightness_cb),
+            )
+            .with_prop(
+                "TemporaryRemaining",
+                Access::Read(get_temporary_remaining_cb),
+            );
+
+        if let Target::Output(reg_name) = target {
+            let get_power_cb = move |ctx: PropContext<State>| {
+                Ok::<_, MethodError>(output(ctx.state, reg_name)?.power())
+            };
 
-                let output = ctx.state.mut_output_by_reg_name(reg_name).unwrap();
-                if let Some(new_color) = output.color().with_updated_temp(args.delta) {
-                    output.set_color(new_color);
+            let set_power_cb = move |ctx: PropContext<State>, val: UnVariant| {
+                check_write(ctx.state, &ctx.msg)?;
+                let power = prop_value(&val)?;
+                if !output(ctx.state, reg_name)?.power_control() {
+                    return Err(MethodError::new(
+                        "org.freedesktop.DBus.Error.NotSupported",
+                        "the backend cannot turn this output on or off".to_owned(),
+                    ));
+                }
+                // The change is signaled once the backend reports it
+                if let Some(output) = ctx.state.mut_output_by_reg_name(reg_name) {
+                    output.request_power(power);
+                }
+                Ok::<_, MethodError>(())
+            };
 
-                    let value = new_color.temp.into();
-                    signal_change(ctx.conn, ctx.object_path, "Temperature", value);
+            let get_managed_cb = move |ctx: PropContext<State>| {
+                Ok::<_, MethodError>(output(ctx.state, reg_name)?.managed())
+            };
 
-                    let temp = ctx.state.color().temp;
-                    if temp != global_color.temp {
-                        let value = temp.into();
-                        signal_change(ctx.conn, "/", "Temperature", value);
-                    }
+            let set_managed_cb = move |ctx: PropContext<State>, val: UnVariant| {
+                check_write(ctx.state, &ctx.msg)?;
+                let managed = prop_value(&val)?;
+                if output(ctx.state, reg_name)?.managed() == managed {
+                    return Ok(());
+                }
+                let old_aggregates = aggregate_colors(ctx.state);
+                ctx.state
+                    .update_output_setting(reg_name, |setting| setting.managed = managed)
+                    .map_err(failed)?;
+                if let Some(path) = output(ctx.state, reg_name)?.object_path() {
+                    signal_change(ctx.conn, &path, "Managed", managed.into());
                 }
+                signal_aggregate_changes(ctx.conn, &old_aggregates, &aggregate_colors(ctx.state));
+                Ok::<_, MethodError>(())
             };
 
-        let get_temperature_output_cb = move |ctx: PropContext<WaylandState>| {
-            ctx.state.output_by_reg_name(reg_name).unwrap().color().temp
-        };
+            let toggle_enabled_cb = move |ctx: &mut MethodContext<State>, _args: ()| {
+                check_write(ctx.state, &ctx.msg)?;
+                let enabled = !output(ctx.state, reg_name)?.enabled();
+                set_output_enabled(ctx.conn, ctx.state, reg_name, enabled)
+            };
 
-        let set_temperature_output_cb = move |ctx: PropContext<WaylandState>, val: UnVariant| {
-            let global_color = ctx.state.color();
+            let get_enabled_cb = move |ctx: PropContext<State>| {
+                Ok::<_, MethodError>(output(ctx.state, reg_name)?.enabled())
+            };
 
-            let output = ctx.state.mut_output_by_reg_name(reg_name).unwrap();
-            let color = output.color();
-            let temp = val.get::<u16>().unwrap().clamp(1_000, 10_000);
+            let set_enabled_cb = move |ctx: PropContext<State>, val: UnVariant| {
+                check_write(ctx.state, &ctx.msg)?;
+                set_output_enabled(ctx.conn, ctx.state, reg_name, prop_value(&val)?)
+            };
 
-            if color.temp != temp {
-                output.set_color(Color { temp, ..color });
+            let get_temperature_offset_cb = move |ctx: PropContext<State>| {
+                Ok::<_, MethodError>(output(ctx.state, reg_name)?.calibration().temp_offset)
+            };
 
-                let value = temp.into();
-                signal_change(ctx.conn, ctx.object_path, "Temperature", value);
+            let set_temperature_offset_cb = move |ctx: PropContext<State>, val: UnVariant| {
+                check_write(ctx.state, &ctx.msg)?;
+                let offset: i16 = prop_value(&val)?;
+                set_calibration(ctx.conn, ctx.state, reg_name, |c| c.temp_offset = offset)
+            };
 
-                let temp = ctx.state.color().temp;
-                if temp != global_color.temp {
-                    let value = temp.into();
-                    signal_change(ctx.conn, "/", "Temperature", value);
-                }
-            }
-        };
+            let get_gamma_scale_cb = move |ctx: PropContext<State>| {
+                Ok::<_, MethodError>(output(ctx.state, reg_name)?.calibration().gamma_scale)
+            };
 
-        let update_gamma_output_cb =
-            move |ctx: &mut MethodContext<WaylandState>, args: UpdateGammaArgs| {
-                let global_color = ctx.state.color();
+            let set_gamma_scale_cb = move |ctx: PropContext<State>, val: UnVariant| {
+                check_write(ctx.state, &ctx.msg)?;
+                let scale: f64 = prop_value(&val)?;
+                set_calibration(ctx.conn, ctx.state, reg_name, |c| c.gamma_scale = scale)
+            };
 
-                let output = ctx.state.mut_output_by_reg_name(reg_name).unwrap();
-                let color = output.color();
-                let gamma = (color.gamma + args.delta).max(0.1);
+            let get_brightness_scale_cb = move |ctx: PropContext<State>| {
+                Ok::<_, MethodError>(output(ctx.state, reg_name)?.calibration().brightness_scale)
+            };
 
-                if color.gamma != gamma {
-                    output.set_color(Color { gamma, ..color });
+            let set_brightness_scale_cb = move |ctx: PropContext<State>, val: UnVariant| {
+                check_write(ctx.state, &ctx.msg)?;
+                let scale: f64 = prop_value(&val)?;
+                set_calibration(ctx.conn, ctx.state, reg_name, |c| {
+                    c.brightness_scale = scale
+                })
+            };
 
-                    let value = gamma.into();
-                    signal_change(ctx.conn, ctx.object_path, "Gamma", value);
+            let link_to_cb = move |ctx: &mut MethodContext<State>, args: LinkToArgs| {
+                check_write(ctx.state, &ctx.msg)?;
+                let offsets = ColorDeltas::default();
+                link_output(ctx.conn, ctx.state, reg_name, args.source, offsets)
+            };
 
-                    let gamma = ctx.state.color().gamma;
-                    if gamma != global_color.gamma {
-                        let value = gamma.into();
-                        signal_change(ctx.conn, "/", "Gamma", value);
+            let link_to_with_offsets_cb =
+                move |ctx: &mut MethodContext<State>, args: LinkToWithOffsetsArgs| {
+                    check_write(ctx.state, &ctx.msg)?;
+                    let offsets = color_deltas(&args.offsets)?;
+                    if !offsets.gamma.is_finite() || !offsets.brightness.is_finite() {
+                        return Err(invalid_args("offsets must be finite"));
                     }
+                    link_output(ctx.conn, ctx.state, reg_name, args.source, offsets)
+                };
+
+            let unlink_cb = move |ctx: &mut MethodContext<State>, _args: ()| {
+                check_write(ctx.state, &ctx.msg)?;
+                let path = output(ctx.state, reg_name)?.object_path();
+                if ctx.state.unlink_output(reg_name)
+                    && let Some(path) = path
+                {
+                    signal_change(ctx.conn, &path, "LinkedTo", "".into());
                 }
+                Ok::<_, MethodError>(())
             };
 
-        let get_gamma_output_cb = move |ctx: PropContext<WaylandState>| {
-            ctx.state
-                .output_by_reg_name(reg_name)
-                .unwrap()
-                .color()
-                .gamma
-        };
+            let get_linked_to_cb = move |ctx: PropContext<State>| {
+                Ok::<_, MethodError>(linked_to(output(ctx.state, reg_name)?))
+            };
 
-        let set_gamma_output_cb = move |ctx: PropContext<WaylandState>, val: UnVariant| {
-            let global_color = ctx.state.color();
+            iface = iface
+                .with_method::<LinkToArgs, ()>("LinkTo", link_to_cb)
+                .with_method::<LinkToWithOffsetsArgs, ()>(
+                    "LinkToWithOffsets",
+                    link_to_with_offsets_cb,
+                )
+                .with_method::<(), ()>("Unlink", unlink_cb)
+                .with_prop("LinkedTo", Access::Read(get_linked_to_cb))
+                .with_prop(
+                    "TemperatureOffset",
+                    Access::ReadWrite(get_temperature_offset_cb, set_temperature_offset_cb),
+                )
+                .with_prop(
+                    "GammaScale",
+                    Access::ReadWrite(get_gamma_scale_cb, set_gamma_scale_cb),
+                )
+                .with_prop(
+                    "BrightnessScale",
+                    Access::ReadWrite(get_brightness_scale_cb, set_brightness_scale_cb),
+                )
+                .with_method::<(), ()>("ToggleEnabled", toggle_enabled_cb)
+                .with_prop("Enabled", Access::ReadWrite(get_enabled_cb, set_enabled_cb))
+                .with_prop("Power", Access::ReadWrite(get_power_cb, set_power_cb))
+                .with_prop("Managed", Access::ReadWrite(get_managed_cb, set_managed_cb));
+        }
 
-            let output = ctx.state.mut_output_by_reg_name(reg_name).unwrap();
-            let color = output.color();
-            let gamma = val.get::<f64>().unwrap().max(0.1);
+        let mut object = rustbus_service::Object::new();
+        object.add_interface(iface);
 
-            if color.gamma != gamma {
-                output.set_color(Color { gamma, ..color });
+        let (parent, name) = path.rsplit_once('/').unwrap();
+        let parent_object = self
+            .service
+            .get_object_mut(parent)
+            .unwrap_or_else(|| panic!("object {parent} not found"));
+        parent_object.add_child(name, object);
+    }
 
-                let value = gamma.into();
-                signal_change(ctx.conn, ctx.object_path, "Gamma", value);
+    fn remove_color_object(&mut self, path: &str) {
+        let (parent, name) = path.rsplit_once('/').unwrap();
+        let parent_object = self
+            .service
+            .get_object_mut(parent)
+            .unwrap_or_else(|| panic!("object {parent} not found"));
+        parent_object.remove_child(name);
+
+        let mut sig = MessageBuilder::new()
+            .signal(OBJECT_MANAGER_IFACE, "InterfacesRemoved", "/")
+            .build();
+        sig.body.push_param(ObjectPath::new(path).unwrap()).unwrap();
+        sig.body.push_param(&["rs.wl.gammarelay"][..]).unwrap();
+        self.conn.send.send_message_write_all(&sig).unwrap();
+    }
 
-                let gamma = ctx.state.color().gamma;
-                if gamma != global_color.gamma {
-                    let value = gamma.into();
-                    signal_change(ctx.conn, "/", "Gamma", value);
-                }
-            }
-        };
+    fn signal_interfaces_added(
+        &mut self,
+        path: &str,
+        props: HashMap<&'static str, Variant<'static, 'static>>,
+    ) {
+        let mut interfaces = HashMap::new();
+        interfaces.insert("rs.wl.gammarelay", props);
+        let mut sig = MessageBuilder::new()
+            .signal(OBJECT_MANAGER_IFACE, "InterfacesAdded", "/")
+            .build();
+        sig.body.push_param(ObjectPath::new(path).unwrap()).unwrap();
+        sig.body.push_param(interfaces).unwrap();
+        self.conn.send.send_message_write_all(&sig).unwrap();
+    }
 
-        let gammarelay_output_iface = InterfaceImp::new("rs.wl.gammarelay")
-            .with_method::<(), ()>("ToggleInverted", toggle_inverted_output_cb)
-            .with_method::<UpdateTemperatureArgs, ()>(
-                "UpdateTemperature",
-                update_temperature_output_cb,
-            )
-            .with_method::<UpdateGammaArgs, ()>("UpdateGamma", update_gamma_output_cb)
-            .with_method::<UpdateBrightnessArgs, ()>(
-                "UpdateBrightness",
-                update_brightness_output_cb,
-            )
-            .with_prop(
-                "Inverted",
-                Access::ReadWrite(get_inverted_output_cb, set_inverted_output_cb),
-            )
-            .with_prop(
-                "Temperature",
-                Access::ReadWrite(get_temperature_output_cb, set_temperature_output_cb),
-            )
-            .with_prop(
-                "Gamma",
-                Access::ReadWrite(get_gamma_output_cb, set_gamma_output_cb),
-            )
-            .with_prop(
-                "Brightness",
-                Access::ReadWrite(get_brightness_output_cb, set_brightness_output_cb),
-            );
+    /// Signals the properties of the root and the groups that changed without a DBus call,
+    /// e.g. after hotplug
+    pub fn update_aggregates(&mut self, state: &State) {
+        let colors = aggregate_colors(state);
+        signal_aggregate_changes(&mut self.conn, &self.aggregate_colors, &colors);
+        self.aggregate_colors = colors;
+        self.update_active_preset(state);
+        self.update_uniform(state);
+        self.update_compat(state);
+    }
 
-        let mut object = rustbus_service::Object::new();
-        object.add_interface(gammarelay_output_iface);
+    /// Signals the properties of the compatibility interfaces that changed
+    fn update_compat(&mut self, state: &State) {
+        let gnome = gnome::snapshot(state);
+        gnome.signal_changes(&mut self.conn, gnome::PATH, gnome::IFACE, &self.gnome);
+        self.gnome = gnome;
+        let kde = kde::snapshot(state);
+        kde.signal_changes(&mut self.conn, kde::PATH, kde::IFACE, &self.kde);
+        self.kde = kde;
+    }
 
-        let outputs_object = self
-            .service
-            .get_object_mut("/outputs")
-            .expect("object /outputs not found");
-        outputs_object.add_child(name.replace('-', "_"), object);
+    /// The active preset depends on the colors of all outputs, so it is checked after every
+    /// change instead of by each callback
+    fn update_active_preset(&mut self, state: &State) {
+        let active = active_preset(state);
+        if active != self.active_preset {
+            signal_change(&mut self.conn, "/", "ActivePreset", active.clone().into());
+            self.active_preset = active;
+        }
     }
 
-    pub fn remove_output(&mut self, name: &str) {
-        let outputs_object = self
-            .service
-            .get_object_mut("/outputs")
-            .expect("object /outputs not found");
+    /// Like the active preset, `Uniform` is checked after every change
+    fn update_uniform(&mut self, state: &State) {
+        let uniform = state.uniform();
+        if uniform != self.uniform {
+            signal_change(&mut self.conn, "/", "Uniform", uniform.into());
+            self.uniform = uniform;
+        }
+    }
+
+    pub fn power_changed(&mut self, name: &str, power: bool) {
+        let path = format!("/outputs/{}", object_name(name));
+        signal_change(&mut self.conn, &path, "Power", power.into());
+    }
+
+    /// Handles timers that are due
+    pub fn expire_timers(&mut self, state: &mut State) {
+        if state.expire_temporaries() {
+            signal_temporary(&mut self.conn, state);
+        }
+        let was_inhibited = state.is_inhibited();
+        if state.expire_inhibitors() {
+            signal_inhibitors(&mut self.conn, state, was_inhibited);
+        }
+        self.update_compat(state);
+    }
+
+    /// The file descriptor of the connection used to track disconnecting clients
+    pub fn name_watcher_fd(&self) -> RawFd {
+        self.name_watcher.as_raw_fd()
+    }
+
+    pub fn poll_name_watcher(&mut self, state: &mut State) -> Result<()> {
+        self.handle_name_changes(state)?;
+        self.update_compat(state);
+        Ok(())
+    }
+
+    /// Releases the inhibitors of clients that left the bus, identifies the clients that may
+    /// write, and notices when another instance took over the bus name
+    fn handle_name_changes(&mut self, state: &mut State) -> Result<()> {
+        // Identifying a client may receive more changes
+        loop {
+            let changes = self.name_watcher.poll()?;
+            if changes.is_empty() {
+                return Ok(());
+            }
+            for change in changes {
+                state
+                    .write_policy
+                    .set_owner(&change.name, &change.new_owner);
+                if change.new_owner.is_empty() {
+                    state.write_policy.forget(&change.name);
+                    let was_inhibited = state.is_inhibited();
+                    if state.uninhibit_owner(&change.name) {
+                        signal_inhibitors(&mut self.conn, state, was_inhibited);
+                    }
+                } else if change.name == self.bus_name && change.new_owner != self.unique_name {
+                    self.name_lost = true;
+                } else if change.name == change.new_owner && state.write_policy.needs_executables()
+                {
+                    self.identify_client(state, &change.name);
+                }
+            }
+        }
+    }
+
+    /// Starts tracking the clients that may write, if writing is restricted
+    pub fn watch_writers(&mut self, state: &mut State) -> Result<()> {
+        let names = state
+            .write_policy
+            .well_known_names()
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        for name in names {
+            self.name_watcher.watch_name(&name)?;
+            if let Some(reply) = self
+                .name_watcher
+                .call_bus("GetNameOwner", Some(name.as_str()))?
+            {
+                let owner = reply.body.parser().get::<String>()?;
+                state.write_policy.set_owner(&name, &owner);
+            }
+        }
+        if state.write_policy.needs_executables() {
+            state.write_policy.set_lookup(NameWatcher::connect()?);
+            self.name_watcher.watch_new_names()?;
+            // The clients that connected earlier
+            if let Some(reply) = self.name_watcher.call_bus("ListNames", None)? {
+                for name in reply.body.parser().get::<Vec<String>>()? {
+                    if name.starts_with(':') {
+                        self.identify_client(state, &name);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks up the executable of a client that connected, before it makes any call
+    fn identify_client(&mut self, state: &mut State, client: &str) {
+        match self.name_watcher.process_id(client) {
+            Ok(Some(pid)) => state.write_policy.set_process(client, pid),
+            // The client already left
+            Ok(None) => (),
+            Err(e) => eprintln!("Could not identify {client}: {e:#}"),
+        }
+    }
 
-        outputs_object.remove_child(&name.replace('-', "_"));
+    /// Returns `true` once another instance took over the bus name, after which this one
+    /// should exit
+    pub fn name_lost(&self) -> bool {
+        self.name_lost
     }
 
-    pub fn poll(&mut self, state: &mut WaylandState) -> Result<()> {
+    pub fn poll(&mut self, state: &mut State) -> Result<()> {
+        // Clients are usually identified when they connect, which the bus reports before their
+        // calls, but the signals are received on another connection
+        self.handle_name_changes(state)?;
         self.service.run(&mut self.conn, state, Timeout::Nonblock)?;
+        // Changes made by DBus calls are signaled by the callbacks
+        self.aggregate_colors = aggregate_colors(state);
+        self.update_active_preset(state);
+        self.update_uniform(state);
+        self.update_compat(state);
         Ok(())
     }
 }
 
-fn toggle_inverted_root_cb(ctx: &mut MethodContext<WaylandState>, _args: ()) {
-    let inverted = !ctx.state.color().inverted;
-    ctx.state.set_inverted(inverted);
+/// Returns `false` if another connection owns the name. The name can always be taken over by
+/// an instance started with `replace`.
+fn request_name(
+    conn: &mut DuplexConn,
+    service: &mut Service<State>,
+    name: &str,
+    replace: bool,
+) -> Result<bool> {
+    let mut flags = rustbus::standard_messages::DBUS_NAME_FLAG_ALLOW_REPLACEMENT
+        | rustbus::standard_messages::DBUS_NAME_FLAG_DO_NOT_QUEUE;
+    if replace {
+        flags |= rustbus::standard_messages::DBUS_NAME_FLAG_REPLACE_EXISTING;
+    }
+    let msg = rustbus::standard_messages::request_name(name, flags);
+    let serial = conn.send.send_message_write_all(&msg)?;
+    let reply = service.get_reply(conn, serial, Timeout::Infinite)?;
+    Ok(reply.body.parser().get::<u32>()?
+        == rustbus::standard_messages::DBUS_REQUEST_NAME_REPLY_PRIMARY_OWNER)
+}
+
+/// Returns the unique name of the owner of `name`, if any
+fn name_owner(
+    conn: &mut DuplexConn,
+    service: &mut Service<State>,
+    name: &str,
+) -> Result<Option<String>> {
+    let mut msg = MessageBuilder::new()
+        .call("GetNameOwner")
+        .on("/org/freedesktop/DBus")
+        .with_interface("org.freedesktop.DBus")
+        .at("org.freedesktop.DBus")
+        .build();
+    msg.body.push_param(name)?;
+    let serial = conn.send.send_message_write_all(&msg)?;
+    let reply = service.get_reply(conn, serial, Timeout::Infinite)?;
+    // NameHasNoOwner
+    if reply.typ == MessageType::Error {
+        return Ok(None);
+    }
+    Ok(Some(reply.body.parser().get::<String>()?))
+}
+
+type ManagedObjects = HashMap<
+    ObjectPath<String>,
+    HashMap<&'static str, HashMap<&'static str, Variant<'static, 'static>>>,
+>;
+
+/// Creates empty objects for the missing ancestors of `path`. Returns the path of its parent
+/// and its name.
+fn add_parents<'p>(service: &mut Service<State>, path: &'p str) -> (String, &'p str) {
+    let (parent_path, name) = path.rsplit_once('/').unwrap();
+    let mut current = String::new();
+    for component in parent_path.split('/').skip(1) {
+        let ancestor = if current.is_empty() { "/" } else { &current };
+        let child = format!("{current}/{component}");
+        if service.get_object_mut(&child).is_none() {
+            let ancestor = service.get_object_mut(ancestor).unwrap();
+            ancestor.add_child(component, rustbus_service::Object::new());
+        }
+        current = child;
+    }
+    if current.is_empty() {
+        current.push('/');
+    }
+    (current, name)
+}
+
+fn get_managed_objects_cb(ctx: &mut MethodContext<State>, _args: ()) -> ManagedObjects {
+    let state = &*ctx.state;
+    let outputs = state.outputs.iter().filter_map(|output| {
+        let props = object_properties(state, Target::Output(output.reg_name()))?;
+        Some((output.object_path()?, "rs.wl.gammarelay", props))
+    });
+    let groups = state.groups().iter().filter_map(|group| {
+        let props = object_properties(state, Target::Group(group.id))?;
+        Some((group.object_path(), "rs.wl.gammarelay", props))
+    });
+    let compat = [
+        (gnome::PATH, gnome::IFACE, gnome::snapshot(state)),
+        (kde::PATH, kde::IFACE, kde::snapshot(state)),
+    ]
+    .into_iter()
+    .map(|(path, iface, snapshot)| (path.to_owned(), iface, snapshot.properties()));
+    outputs
+        .chain(groups)
+        .chain(compat)
+        .map(|(path, iface, props)| {
+            let mut interfaces = HashMap::new();
+            interfaces.insert(iface, props);
+            (ObjectPath::new(path).unwrap(), interfaces)
+        })
+        .collect()
+}
+
+fn output_object_path(name: &str) -> String {
+    format!("/outputs/{}", object_name(name))
+}
+
+/// The properties of an output or a group, as returned by `Properties.GetAll`. They have to match
+/// the ones exported by [`DbusServer::add_color_object`].
+fn object_properties(
+    state: &State,
+    target: Target,
+) -> Option<HashMap<&'static str, Variant<'static, 'static>>> {
+    let mut props = color_properties(target_color(state, target).ok()?);
+    let remaining = temporary_remaining(state, target);
+    props.insert("TemporaryRemaining", variant(remaining.into()));
+    if let Target::Output(reg_name) = target {
+        props.extend(output_properties(state.output_by_reg_name(reg_name)?));
+    }
+    Some(props)
+}
+
+/// The properties that only outputs have
+fn output_properties(output: &Output) -> HashMap<&'static str, Variant<'static, 'static>> {
+    let mut props = HashMap::new();
+    props.insert("Power", variant(output.power().into()));
+    props.insert("Managed", variant(output.managed().into()));
+    props.insert("Enabled", variant(output.enabled().into()));
+    props.insert("LinkedTo", variant(linked_to(output).into()));
+    let calibration = output.calibration();
+    props.insert("TemperatureOffset", variant(calibration.temp_offset.into()));
+    props.insert("GammaScale", variant(calibration.gamma_scale.into()));
+    props.insert(
+        "BrightnessScale",
+        variant(calibration.brightness_scale.into()),
+    );
+    props
+}
+
+fn color_properties(color: Color) -> HashMap<&'static str, Variant<'static, 'static>> {
+    let mut props = HashMap::new();
+    props.insert("Temperature", variant(color.temp.into()));
+    props.insert("Gamma", variant(color.gamma.into()));
+    props.insert("Brightness", variant(color.brightness.into()));
+    props.insert("Inverted", variant(color.inverted.into()));
+    props
+}
 
-    signal_change(ctx.conn, ctx.object_path, "Inverted", inverted.into());
-    signal_updated_property_to_outputs(ctx.conn, ctx.state, "Inverted", inverted.into());
+fn variant(value: Param<'static, 'static>) -> Variant<'static, 'static> {
+    Variant {
+        sig: value.sig(),
+        value,
+    }
 }
 
-fn get_inverted_root_cb(ctx: PropContext<WaylandState>) -> bool {
+fn toggle_inverted_root_cb(ctx: &mut MethodContext<State>, _args: ()) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    // Inverts all outputs, unless all of them are inverted already
+    let fields = ColorFields {
+        inverted: Some(!ctx.state.color().inverted),
+        ..Default::default()
+    };
+    set_fields(ctx.conn, ctx.state, sender(&ctx.msg), Target::Root, fields)
+}
+
+fn get_inverted_root_cb(ctx: PropContext<State>) -> bool {
     ctx.state.color().inverted
 }
 
-fn set_inverted_root_cb(ctx: PropContext<WaylandState>, val: UnVariant) {
-    let val = val.get::<bool>().unwrap();
-    if ctx.state.color().inverted != val {
-        ctx.state.set_inverted(val);
+fn set_inverted_root_cb(ctx: PropContext<State>, val: UnVariant) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let fields = ColorFields {
+        inverted: Some(prop_value(&val)?),
+        ..Default::default()
+    };
+    set_fields(ctx.conn, ctx.state, sender(&ctx.msg), Target::Root, fields)
+}
 
-        signal_change(ctx.conn, ctx.object_path, ctx.name, val.into());
-        signal_updated_property_to_outputs(ctx.conn, ctx.state, ctx.name, val.into());
+fn toggle_enabled_root_cb(ctx: &mut MethodContext<State>, _args: ()) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let enabled = !ctx.state.enabled();
+    set_root_enabled(ctx.conn, ctx.state, enabled);
+    Ok(())
+}
+
+fn get_enabled_root_cb(ctx: PropContext<State>) -> bool {
+    ctx.state.enabled()
+}
+
+fn set_enabled_root_cb(ctx: PropContext<State>, val: UnVariant) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    set_root_enabled(ctx.conn, ctx.state, prop_value(&val)?);
+    Ok(())
+}
+
+/// Switches all adjustments off or back on. This is not a color change, so it is not recorded
+/// in the history.
+fn set_root_enabled(conn: &mut DuplexConn, state: &mut State, enabled: bool) {
+    if state.enabled() != enabled {
+        state.set_enabled(enabled);
+        signal_change(conn, "/", "Enabled", enabled.into());
+    }
+}
+
+fn set_output_enabled(
+    conn: &mut DuplexConn,
+    state: &mut State,
+    reg_name: u32,
+    enabled: bool,
+) -> Result<(), MethodError> {
+    let output = output(state, reg_name)?;
+    if output.enabled() == enabled {
+        return Ok(());
+    }
+    let path = output.object_path();
+    if let Some(output) = state.mut_output_by_reg_name(reg_name) {
+        output.set_enabled(enabled);
+    }
+    if let Some(path) = path {
+        signal_change(conn, &path, "Enabled", enabled.into());
+    }
+    Ok(())
+}
+
+#[derive(rustbus_service::Args)]
+struct LinkToArgs<'a> {
+    source: &'a str,
+}
+
+#[derive(rustbus_service::Args)]
+struct LinkToWithOffsetsArgs<'a> {
+    source: &'a str,
+    offsets: HashMap<&'a str, UnVariant<'a, 'a>>,
+}
+
+/// Makes an output follow another one and copies the color of the source right away
+fn link_output(
+    conn: &mut DuplexConn,
+    state: &mut State,
+    reg_name: u32,
+    source: &str,
+    offsets: ColorDeltas,
+) -> Result<(), MethodError> {
+    let path = output(state, reg_name)?.object_path();
+    state
+        .link_output(reg_name, source, offsets)
+        .map_err(invalid_args)?;
+    if let Some(path) = path {
+        signal_change(conn, &path, "LinkedTo", source.into());
     }
+    set_colors(conn, state, |_| None);
+    Ok(())
+}
+
+/// Returns the name of the output that `output` follows, or an empty string
+fn linked_to(output: &Output) -> String {
+    output
+        .link()
+        .map(|link| link.source.clone())
+        .unwrap_or_default()
+}
+
+/// Changes the calibration of an output with `f`, saves it and signals the changed properties.
+/// Calibration changes are not recorded in the history.
+fn set_calibration(
+    conn: &mut DuplexConn,
+    state: &mut State,
+    reg_name: u32,
+    f: impl FnOnce(&mut Calibration),
+) -> Result<(), MethodError> {
+    let output = output(state, reg_name)?;
+    let old = output.calibration();
+    let path = output.object_path();
+    let mut calibration = old;
+    f(&mut calibration);
+    calibration.validate().map_err(invalid_args)?;
+    state
+        .update_output_setting(reg_name, |setting| setting.calibration = calibration)
+        .map_err(failed)?;
+
+    let mut changed = Vec::new();
+    if calibration.temp_offset != old.temp_offset {
+        changed.push(("TemperatureOffset", calibration.temp_offset.into()));
+    }
+    if calibration.gamma_scale != old.gamma_scale {
+        changed.push(("GammaScale", calibration.gamma_scale.into()));
+    }
+    if calibration.brightness_scale != old.brightness_scale {
+        changed.push(("BrightnessScale", calibration.brightness_scale.into()));
+    }
+    if let Some(path) = path
+        && !changed.is_empty()
+    {
+        let sig = prop_changed_message(&path, "rs.wl.gammarelay", changed, &[]);
+        conn.send.send_message_write_all(&sig).unwrap();
+    }
+    Ok(())
 }
 
 #[derive(rustbus_service::Args)]
@@ -359,26 +1012,29 @@ struct UpdateBrightnessArgs {
     delta: f64,
 }
 
-fn update_brightness_root_cb(ctx: &mut MethodContext<WaylandState>, args: UpdateBrightnessArgs) {
-    if ctx.state.update_brightness(args.delta) {
-        let val = ctx.state.color().brightness;
-        signal_change(ctx.conn, ctx.object_path, "Brightness", val.into());
-        signal_updated_property_to_outputs(ctx.conn, ctx.state, "Brightness", val.into());
-    }
+fn update_brightness_root_cb(
+    ctx: &mut MethodContext<State>,
+    args: UpdateBrightnessArgs,
+) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let deltas = ColorDeltas {
+        brightness: args.delta,
+        ..Default::default()
+    };
+    update_deltas(ctx.conn, ctx.state, sender(&ctx.msg), Target::Root, deltas)
 }
 
-fn get_brightness_root_cb(ctx: PropContext<WaylandState>) -> f64 {
+fn get_brightness_root_cb(ctx: PropContext<State>) -> f64 {
     ctx.state.color().brightness
 }
 
-fn set_brightness_root_cb(ctx: PropContext<WaylandState>, val: UnVariant) {
-    let val = val.get::<f64>().unwrap().clamp(0.0, 1.0);
-    if ctx.state.color().brightness != val {
-        ctx.state.set_brightness(val);
-
-        signal_change(ctx.conn, ctx.object_path, ctx.name, val.into());
-        signal_updated_property_to_outputs(ctx.conn, ctx.state, ctx.name, val.into());
-    }
+fn set_brightness_root_cb(ctx: PropContext<State>, val: UnVariant) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let fields = ColorFields {
+        brightness: Some(finite_prop_value(&val)?),
+        ..Default::default()
+    };
+    set_fields(ctx.conn, ctx.state, sender(&ctx.msg), Target::Root, fields)
 }
 
 #[derive(rustbus_service::Args)]
@@ -386,26 +1042,29 @@ struct UpdateTemperatureArgs {
     delta: i16,
 }
 
-fn update_temperature_root_cb(ctx: &mut MethodContext<WaylandState>, args: UpdateTemperatureArgs) {
-    if ctx.state.update_temperature(args.delta) {
-        let val = ctx.state.color().temp;
-        signal_change(ctx.conn, ctx.object_path, "Temperature", val.into());
-        signal_updated_property_to_outputs(ctx.conn, ctx.state, "Temperature", val.into());
-    }
+fn update_temperature_root_cb(
+    ctx: &mut MethodContext<State>,
+    args: UpdateTemperatureArgs,
+) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let deltas = ColorDeltas {
+        temp: args.delta,
+        ..Default::default()
+    };
+    update_deltas(ctx.conn, ctx.state, sender(&ctx.msg), Target::Root, deltas)
 }
 
-fn get_temperature_root_cb(ctx: PropContext<WaylandState>) -> u16 {
+fn get_temperature_root_cb(ctx: PropContext<State>) -> u16 {
     ctx.state.color().temp
 }
 
-fn set_temperature_root_cb(ctx: PropContext<WaylandState>, val: UnVariant) {
-    let val = val.get::<u16>().unwrap().clamp(1_000, 10_000);
-    if ctx.state.color().temp != val {
-        ctx.state.set_temperature(val);
-
-        signal_change(ctx.conn, ctx.object_path, ctx.name, val.into());
-        signal_updated_property_to_outputs(ctx.conn, ctx.state, ctx.name, val.into());
-    }
+fn set_temperature_root_cb(ctx: PropContext<State>, val: UnVariant) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let fields = ColorFields {
+        temp: Some(prop_value(&val)?),
+        ..Default::default()
+    };
+    set_fields(ctx.conn, ctx.state, sender(&ctx.msg), Target::Root, fields)
 }
 
 #[derive(rustbus_service::Args)]
@@ -413,61 +1072,828 @@ struct UpdateGammaArgs {
     delta: f64,
 }
 
-fn update_gamma_root_cb(ctx: &mut MethodContext<WaylandState>, args: UpdateGammaArgs) {
-    if ctx.state.update_gamma(args.delta) {
-        let val = ctx.state.color().gamma;
-        signal_change(ctx.conn, ctx.object_path, "Gamma", val.into());
-        signal_updated_property_to_outputs(ctx.conn, ctx.state, "Gamma", val.into());
-    }
+fn update_gamma_root_cb(
+    ctx: &mut MethodContext<State>,
+    args: UpdateGammaArgs,
+) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let deltas = ColorDeltas {
+        gamma: args.delta,
+        ..Default::default()
+    };
+    update_deltas(ctx.conn, ctx.state, sender(&ctx.msg), Target::Root, deltas)
 }
 
-fn get_gamma_root_cb(ctx: PropContext<WaylandState>) -> f64 {
+fn get_gamma_root_cb(ctx: PropContext<State>) -> f64 {
     ctx.state.color().gamma
 }
 
-fn set_gamma_root_cb(ctx: PropContext<WaylandState>, val: UnVariant) {
-    let val = val.get::<f64>().unwrap().max(0.1);
-    if ctx.state.color().gamma != val {
-        ctx.state.set_gamma(val);
+fn set_gamma_root_cb(ctx: PropContext<State>, val: UnVariant) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let fields = ColorFields {
+        gamma: Some(finite_prop_value(&val)?),
+        ..Default::default()
+    };
+    set_fields(ctx.conn, ctx.state, sender(&ctx.msg), Target::Root, fields)
+}
+
+#[derive(rustbus_service::Args)]
+struct ColorArgs<'a> {
+    color: HashMap<&'a str, UnVariant<'a, 'a>>,
+}
 
+fn set_color_root_cb(ctx: &mut MethodContext<State>, args: ColorArgs) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let fields = color_fields(&args.color)?;
+    set_fields(ctx.conn, ctx.state, sender(&ctx.msg), Target::Root, fields)
+}
+
+fn update_color_root_cb(
+    ctx: &mut MethodContext<State>,
+    args: ColorArgs,
+) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let deltas = color_deltas(&args.color)?;
+    update_deltas(ctx.conn, ctx.state, sender(&ctx.msg), Target::Root, deltas)
+}
+
+/// Parses the argument of `SetColor`, which maps property names to new values
+fn color_fields(dict: &HashMap<&str, UnVariant>) -> Result<ColorFields, MethodError> {
+    let mut fields = ColorFields::default();
+    for (&key, value) in dict {
+        match key {
+            "Temperature" => fields.temp = Some(value.get().map_err(invalid_args)?),
+            "Gamma" => fields.gamma = Some(value.get().map_err(invalid_args)?),
+            "Brightness" => fields.brightness = Some(value.get().map_err(invalid_args)?),
+            "Inverted" => fields.inverted = Some(value.get().map_err(invalid_args)?),
+            _ => return Err(invalid_args(format!("unknown property {key:?}"))),
+        }
+    }
+    fields.validate().map_err(invalid_args)?;
+    Ok(fields)
+}
+
+/// Parses the argument of `UpdateColor`, which maps property names to deltas, like the ones
+/// passed to the `Update*` methods. `Inverted` is toggled if `true`.
+fn color_deltas(dict: &HashMap<&str, UnVariant>) -> Result<ColorDeltas, MethodError> {
+    let mut deltas = ColorDeltas::default();
+    for (&key, value) in dict {
+        match key {
+            "Temperature" => deltas.temp = value.get().map_err(invalid_args)?,
+            "Gamma" => deltas.gamma = value.get().map_err(invalid_args)?,
+            "Brightness" => deltas.brightness = value.get().map_err(invalid_args)?,
+            "Inverted" => deltas.toggle_inverted = value.get().map_err(invalid_args)?,
+            _ => return Err(invalid_args(format!("unknown property {key:?}"))),
+        }
+    }
+    Ok(deltas)
+}
+
+/// Sets some of the color parameters of the outputs of `target`
+fn set_fields(
+    conn: &mut DuplexConn,
+    state: &mut State,
+    sender: &str,
+    target: Target,
+    fields: ColorFields,
+) -> Result<(), MethodError> {
+    change_target(conn, state, sender, target, |c| c.with_fields(fields))
+}
+
+/// Applies deltas to the colors of the outputs of `target`
+fn update_deltas(
+    conn: &mut DuplexConn,
+    state: &mut State,
+    sender: &str,
+    target: Target,
+    deltas: ColorDeltas,
+) -> Result<(), MethodError> {
+    if !deltas.gamma.is_finite() || !deltas.brightness.is_finite() {
+        return Err(invalid_args("deltas must be finite"));
+    }
+    change_target(conn, state, sender, target, |c| c.with_deltas(deltas))
+}
+
+fn change_target(
+    conn: &mut DuplexConn,
+    state: &mut State,
+    sender: &str,
+    target: Target,
+    f: impl Fn(Color) -> Color,
+) -> Result<(), MethodError> {
+    target_color(state, target)?;
+    // The color of a linked output is always copied from its source
+    if let Target::Output(reg_name) = target
+        && let Some(link) = output(state, reg_name)?.link()
+    {
+        return Err(invalid_args(format!(
+            "the output is linked to {}, unlink it first",
+            link.source
+        )));
+    }
+    let members = state.members(target);
+    change_colors(conn, state, sender, target, |output| {
+        members
+            .contains(&output.reg_name())
+            .then(|| f(output.color()))
+    });
+    Ok(())
+}
+
+/// Changes the color of every output for which `f` returns a new color, signals the change and
+/// records it in the history. `target` is the object the change was requested on.
+fn change_colors(
+    conn: &mut DuplexConn,
+    state: &mut State,
+    sender: &str,
+    target: Target,
+    f: impl FnMut(&Output) -> Option<Color>,
+) {
+    let old_color = state.target_color(target);
+    let outputs = set_colors(conn, state, f);
+    if outputs.is_empty() {
+        return;
+    }
+    state.history.record(HistoryEntry {
+        time: SystemTime::now(),
+        sender: sender.to_owned(),
+        path: state.target_path(target).unwrap_or_default(),
+        old: old_color,
+        new: state.target_color(target),
+        outputs,
+    });
+}
+
+/// Changes the color of every output for which `f` returns a new color, clamped to the limits,
+/// updates the linked outputs and emits a single `PropertiesChanged` signal for every object
+/// whose properties changed. Returns the named outputs whose color changed, with their old and
+/// new color.
+fn set_colors(
+    conn: &mut DuplexConn,
+    state: &mut State,
+    mut f: impl FnMut(&Output) -> Option<Color>,
+) -> Vec<(String, Color, Color)> {
+    let old_aggregates = aggregate_colors(state);
+    let old_colors = state
+        .outputs
+        .iter()
+        .map(|output| output.color())
+        .collect::<Vec<_>>();
+    for output in &mut state.outputs {
+        if let Some(color) = f(output) {
+            output.set_color(state.limits.clamp(color));
+        }
+    }
+    state.follow_links();
+
+    let changes = state
+        .outputs
+        .iter()
+        .zip(old_colors)
+        .filter_map(|(output, old_color)| {
+            let name = output.name()?;
+            (old_color != output.color()).then(|| (name.to_owned(), old_color, output.color()))
+        })
+        .collect::<Vec<_>>();
+
+    for (name, old_color, new_color) in &changes {
+        let path = output_object_path(name);
+        signal_color_change(conn, &path, *old_color, *new_color);
+    }
+    signal_aggregate_changes(conn, &old_aggregates, &aggregate_colors(state));
+    changes
+}
+
+/// Returns the colors of the root and of every group, by object path
+fn aggregate_colors(state: &State) -> Vec<(String, Color)> {
+    let groups = state.groups().iter().map(|group| {
+        let color = state.target_color(Target::Group(group.id));
+        (group.object_path(), color)
+    });
+    std::iter::once(("/".to_owned(), state.color()))
+        .chain(groups)
+        .collect()
+}
+
+/// Signals the changes between two results of [`aggregate_colors`]
+fn signal_aggregate_changes(
+    conn: &mut DuplexConn,
+    old: &[(String, Color)],
+    new: &[(String, Color)],
+) {
+    for (path, new_color) in new {
+        if let Some((_, old_color)) = old.iter().find(|(p, _)| p == path) {
+            signal_color_change(conn, path, *old_color, *new_color);
+        }
+    }
+}
+
+/// Returns the color of `target`, or an error if its object was removed
+fn target_color(state: &State, target: Target) -> Result<Color, MethodError> {
+    if state.target_exists(target) {
+        Ok(state.target_color(target))
+    } else {
+        Err(unknown_object())
+    }
+}
+
+/// Emits `PropertiesChanged` with all properties that differ between two colors, if any
+fn signal_color_change(conn: &mut DuplexConn, path: &str, old: Color, new: Color) {
+    let changed = changed_properties(old, new);
+    if !changed.is_empty() {
+        let sig = prop_changed_message(path, "rs.wl.gammarelay", changed, &[]);
+        conn.send.send_message_write_all(&sig).unwrap();
+    }
+}
+
+/// Returns the properties that differ between two colors, with their new values
+fn changed_properties(old: Color, new: Color) -> Vec<(&'static str, Param<'static, 'static>)> {
+    let mut changed = Vec::new();
+    if old.temp != new.temp {
+        changed.push(("Temperature", new.temp.into()));
+    }
+    if old.gamma != new.gamma {
+        changed.push(("Gamma", new.gamma.into()));
+    }
+    if old.brightness != new.brightness {
+        changed.push(("Brightness", new.brightness.into()));
+    }
+    if old.inverted != new.inverted {
+        changed.push(("Inverted", new.inverted.into()));
+    }
+    changed
+}
+
+#[derive(rustbus_service::Args)]
+struct SetTemporaryArgs<'a> {
+    color: HashMap<&'a str, UnVariant<'a, 'a>>,
+    seconds: u32,
+}
+
+fn set_temporary_root_cb(
+    ctx: &mut MethodContext<State>,
+    args: SetTemporaryArgs,
+) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    set_temporary(ctx, Target::Root, args)
+}
+
+fn set_temporary(
+    ctx: &mut MethodContext<State>,
+    target: Target,
+    args: SetTemporaryArgs,
+) -> Result<(), MethodError> {
+    if args.seconds == 0 {
+        return Err(invalid_args("the duration must not be zero"));
+    }
+    target_color(ctx.state, target)?;
+    let fields = color_fields(&args.color)?;
+    let duration = Duration::from_secs(args.seconds.into());
+    ctx.state.set_temporary(target, fields, duration);
+    signal_temporary(ctx.conn, ctx.state);
+    Ok(())
+}
+
+fn cancel_temporary_root_cb(ctx: &mut MethodContext<State>, _args: ()) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    if ctx.state.cancel_temporary(Target::Root) {
+        signal_temporary(ctx.conn, ctx.state);
+    }
+    Ok(())
+}
+
+fn get_temporary_remaining_root_cb(ctx: PropContext<State>) -> u32 {
+    temporary_remaining(ctx.state, Target::Root)
+}
+
+/// Returns the longest remaining time of the outputs of `target`
+fn temporary_remaining(state: &State, target: Target) -> u32 {
+    state
+        .members(target)
+        .into_iter()
+        .filter_map(|reg_name| state.output_by_reg_name(reg_name))
+        .map(|output| ceil_secs(output.temporary_remaining()))
+        .max()
+        .unwrap_or(0)
+}
+
+fn ceil_secs(duration: Duration) -> u32 {
+    duration
+        .as_millis()
+        .div_ceil(1000)
+        .try_into()
+        .unwrap_or(u32::MAX)
+}
+
+/// Signals `TemporaryRemaining` of all objects. It is only signaled when a temporary color is
+/// set or reverted, not every second.
+fn signal_temporary(conn: &mut DuplexConn, state: &State) {
+    for output in &state.outputs {
+        if let Some(path) = output.object_path() {
+            let remaining = ceil_secs(output.temporary_remaining());
+            signal_change(conn, &path, "TemporaryRemaining", remaining.into());
+        }
+    }
+    for group in state.groups() {
+        let remaining = temporary_remaining(state, Target::Group(group.id));
+        signal_change(
+            conn,
+            &group.object_path(),
+            "TemporaryRemaining",
+            remaining.into(),
+        );
+    }
+    let remaining = temporary_remaining(state, Target::Root);
+    signal_change(conn, "/", "TemporaryRemaining", remaining.into());
+}
+
+#[derive(rustbus_service::Args)]
+struct InhibitArgs<'a> {
+    reason: &'a str,
+}
+
+fn inhibit_cb(ctx: &mut MethodContext<State>, args: InhibitArgs) -> Result<u32, MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let was_inhibited = ctx.state.is_inhibited();
+    let cookie = ctx.state.inhibit(sender(&ctx.msg), args.reason);
+    signal_inhibitors(ctx.conn, ctx.state, was_inhibited);
+    Ok(cookie)
+}
+
+#[derive(rustbus_service::Args)]
+struct UnInhibitArgs {
+    cookie: u32,
+}
+
+fn uninhibit_cb(ctx: &mut MethodContext<State>, args: UnInhibitArgs) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let was_inhibited = ctx.state.is_inhibited();
+    if !ctx.state.uninhibit(args.cookie) {
+        return Err(invalid_args(format!(
+            "no inhibitor with cookie {}",
+            args.cookie
+        )));
+    }
+    signal_inhibitors(ctx.conn, ctx.state, was_inhibited);
+    Ok(())
+}
+
+fn get_inhibited_cb(ctx: PropContext<State>) -> bool {
+    ctx.state.is_inhibited()
+}
+
+fn get_inhibitors_cb(ctx: PropContext<State>) -> Vec<(u32, String, String)> {
+    inhibitors(ctx.state)
+}
+
+/// Returns the inhibitors as (cookie, bus name, reason)
+fn inhibitors(state: &State) -> Vec<(u32, String, String)> {
+    state
+        .inhibitors()
+        .iter()
+        .map(|i| (i.cookie, i.owner.clone(), i.reason.clone()))
+        .collect()
+}
+
+/// `Inhibitors` is only invalidated, clients have to get its new value
+fn signal_inhibitors(conn: &mut DuplexConn, state: &State, was_inhibited: bool) {
+    let mut changed = Vec::new();
+    if state.is_inhibited() != was_inhibited {
+        changed.push(("Inhibited", state.is_inhibited().into()));
+    }
+    let sig = prop_changed_message("/", "rs.wl.gammarelay", changed, &["Inhibitors"]);
+    conn.send.send_message_write_all(&sig).unwrap();
+}
+
+/// Returns the unique bus name of the caller
+fn sender(msg: &MarshalledMessage) -> &str {
+    msg.dynheader.sender.as_deref().unwrap_or_default()
+}
+
+/// Fails with `AccessDenied` unless the caller may change settings
+fn check_write(state: &mut State, msg: &MarshalledMessage) -> Result<(), MethodError> {
+    state.write_policy.check(sender(msg))
+}
+
+#[derive(rustbus_service::Args)]
+struct PresetArgs<'a> {
+    name: &'a str,
+}
+
+fn save_preset_cb(ctx: &mut MethodContext<State>, args: PresetArgs) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    if args.name.is_empty() {
+        return Err(invalid_args("preset name is empty"));
+    }
+    let preset = Preset::capture(args.name, ctx.state);
+    ctx.state.presets.insert(preset).map_err(failed)
+}
+
+fn apply_preset_cb(ctx: &mut MethodContext<State>, args: PresetArgs) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let preset = ctx
+        .state
+        .presets
+        .get(args.name)
+        .cloned()
+        .ok_or_else(|| invalid_args(format!("no preset named {:?}", args.name)))?;
+    apply_preset(ctx.conn, ctx.state, sender(&ctx.msg), &preset);
+    Ok(())
+}
+
+fn delete_preset_cb(ctx: &mut MethodContext<State>, args: PresetArgs) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    if ctx.state.presets.remove(args.name).map_err(failed)? {
+        Ok(())
+    } else {
+        Err(invalid_args(format!("no preset named {:?}", args.name)))
+    }
+}
+
+fn list_presets_cb(ctx: &mut MethodContext<State>, _args: ()) -> Vec<String> {
+    ctx.state.presets.names()
+}
+
+#[derive(rustbus_service::Args)]
+struct CyclePresetsArgs {
+    forward: bool,
+}
+
+fn cycle_presets_cb(
+    ctx: &mut MethodContext<State>,
+    args: CyclePresetsArgs,
+) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let active = active_preset(ctx.state);
+    let active = (!active.is_empty()).then_some(active.as_str());
+    if let Some(preset) = ctx.state.presets.cycle(active, args.forward).cloned() {
+        apply_preset(ctx.conn, ctx.state, sender(&ctx.msg), &preset);
+    }
+    Ok(())
+}
+
+fn get_active_preset_cb(ctx: PropContext<State>) -> String {
+    active_preset(ctx.state)
+}
+
+/// Returns the name of the active preset, or an empty string
+fn active_preset(state: &State) -> String {
+    state
+        .presets
+        .active(state)
+        .map(|preset| preset.name.clone())
+        .unwrap_or_default()
+}
+
+fn apply_preset(conn: &mut DuplexConn, state: &mut State, sender: &str, preset: &Preset) {
+    change_colors(conn, state, sender, Target::Root, |output| {
+        preset.outputs.get(output.name()?).copied()
+    });
+}
+
+#[derive(rustbus_service::Args)]
+struct CreateGroupArgs<'a> {
+    name: &'a str,
+    outputs: Vec<String>,
+}
+
+fn create_group_cb(
+    ctx: &mut MethodContext<State>,
+    args: CreateGroupArgs,
+) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    ctx.state
+        .create_group(args.name, args.outputs)
+        .map(drop)
+        .map_err(invalid_args)
+}
+
+#[derive(rustbus_service::Args)]
+struct DeleteGroupArgs<'a> {
+    name: &'a str,
+}
+
+fn delete_group_cb(
+    ctx: &mut MethodContext<State>,
+    args: DeleteGroupArgs,
+) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    if ctx.state.delete_group(args.name) {
+        Ok(())
+    } else {
+        Err(invalid_args(format!("no group named {:?}", args.name)))
+    }
+}
+
+fn undo_cb(ctx: &mut MethodContext<State>, _args: ()) -> Result<bool, MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let Some(colors) = ctx.state.history.undo() else {
+        return Ok(false);
+    };
+    restore_colors(ctx.conn, ctx.state, &colors);
+    Ok(true)
+}
+
+fn redo_cb(ctx: &mut MethodContext<State>, _args: ()) -> Result<bool, MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let Some(colors) = ctx.state.history.redo() else {
+        return Ok(false);
+    };
+    restore_colors(ctx.conn, ctx.state, &colors);
+    Ok(true)
+}
+
+/// Sets the colors of the outputs with the given names, without recording a new change
+fn restore_colors(conn: &mut DuplexConn, state: &mut State, colors: &[(String, Color)]) {
+    set_colors(conn, state, |output| {
+        let name = output.name()?;
+        colors
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|&(_, color)| color)
+    });
+}
+
+type HistoryList = Vec<(
+    u64,
+    String,
+    HashMap<&'static str, Variant<'static, 'static>>,
+)>;
+
+/// Returns the history as (unix time, sender, changes), oldest first. The changes hold the new
+/// values of the properties that changed on the object the change was requested on, its
+/// `Path`, and whether the change was `Undone`.
+fn get_history_cb(ctx: &mut MethodContext<State>, _args: ()) -> HistoryList {
+    ctx.state
+        .history
+        .entries()
+        .map(|(entry, undone)| {
+            let time = entry
+                .time
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            let mut changes = HashMap::new();
+            changes.insert("Path", variant(entry.path.clone().into()));
+            changes.insert("Undone", variant(undone.into()));
+            for (prop, value) in changed_properties(entry.old, entry.new) {
+                changes.insert(prop, variant(value));
+            }
+            (time, entry.sender.clone(), changes)
+        })
+        .collect()
+}
+
+#[derive(rustbus_service::Args)]
+struct SetAppRuleArgs<'a> {
+    name: &'a str,
+    rule: HashMap<&'a str, UnVariant<'a, 'a>>,
+}
+
+fn set_app_rule_cb(
+    ctx: &mut MethodContext<State>,
+    args: SetAppRuleArgs,
+) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let rule = AppRule::from_dict(args.name, &args.rule).map_err(invalid_args)?;
+    ctx.state.set_app_rule(rule);
+    Ok(())
+}
+
+#[derive(rustbus_service::Args)]
+struct RemoveAppRuleArgs<'a> {
+    name: &'a str,
+}
+
+fn remove_app_rule_cb(
+    ctx: &mut MethodContext<State>,
+    args: RemoveAppRuleArgs,
+) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    if ctx.state.remove_app_rule(args.name) {
+        Ok(())
+    } else {
+        Err(invalid_args(format!("no app rule named {:?}", args.name)))
+    }
+}
+
+type AppRuleList = Vec<(String, HashMap<&'static str, Variant<'static, 'static>>)>;
+
+fn list_app_rules_cb(ctx: &mut MethodContext<State>, _args: ()) -> AppRuleList {
+    ctx.state
+        .app_rules()
+        .iter()
+        .map(|rule| (rule.name.clone(), rule.to_dict()))
+        .collect()
+}
+
+fn get_min_temperature_cb(ctx: PropContext<State>) -> u16 {
+    ctx.state.limits.min_temp
+}
+
+fn get_max_temperature_cb(ctx: PropContext<State>) -> u16 {
+    ctx.state.limits.max_temp
+}
+
+fn get_min_gamma_cb(ctx: PropContext<State>) -> f64 {
+    ctx.state.limits.min_gamma
+}
+
+fn get_max_gamma_cb(ctx: PropContext<State>) -> f64 {
+    ctx.state.limits.max_gamma
+}
+
+fn get_min_brightness_cb(ctx: PropContext<State>) -> f64 {
+    ctx.state.limits.min_brightness
+}
+
+fn get_max_brightness_cb(ctx: PropContext<State>) -> f64 {
+    ctx.state.limits.max_brightness
+}
+
+fn get_root_aggregation_cb(ctx: PropContext<State>) -> String {
+    ctx.state.aggregation.as_str().to_owned()
+}
+
+fn set_root_aggregation_cb(ctx: PropContext<State>, val: UnVariant) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let name: &str = prop_value(&val)?;
+    let aggregation = Aggregation::from_name(name)
+        .ok_or_else(|| invalid_args(format!("unknown aggregation {name:?}")))?;
+    if aggregation == ctx.state.aggregation {
+        return Ok(());
+    }
+    let old_aggregates = aggregate_colors(ctx.state);
+    ctx.state.aggregation = aggregation;
+    signal_change(ctx.conn, "/", "RootAggregation", name.into());
+    signal_aggregate_changes(ctx.conn, &old_aggregates, &aggregate_colors(ctx.state));
+    Ok(())
+}
+
+fn get_uniform_cb(ctx: PropContext<State>) -> bool {
+    ctx.state.uniform()
+}
+
+fn get_idle_dim_timeout_cb(ctx: PropContext<State>) -> u32 {
+    ctx.state.idle_dim.timeout
+}
+
+fn set_idle_dim_timeout_cb(ctx: PropContext<State>, val: UnVariant) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let val = prop_value::<u32>(&val)?;
+    if ctx.state.idle_dim.timeout != val {
+        ctx.state.idle_dim.timeout = val;
         signal_change(ctx.conn, ctx.object_path, ctx.name, val.into());
-        signal_updated_property_to_outputs(ctx.conn, ctx.state, ctx.name, val.into());
     }
+    Ok(())
 }
 
-fn prop_changed_message(path: &str, iface: &str, prop: &str, value: Param) -> MarshalledMessage {
-    let mut map = HashMap::new();
-    map.insert(
-        prop,
-        Variant {
-            sig: value.sig(),
-            value,
-        },
-    );
+fn get_idle_dim_brightness_cb(ctx: PropContext<State>) -> f64 {
+    ctx.state.idle_dim.brightness
+}
+
+fn set_idle_dim_brightness_cb(ctx: PropContext<State>, val: UnVariant) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let val = prop_value::<f64>(&val)?;
+    if !val.is_finite() {
+        return Err(invalid_args("the brightness must be finite"));
+    }
+    let val = val.clamp(0.0, 1.0);
+    if ctx.state.idle_dim.brightness != val {
+        ctx.state.set_idle_dim_brightness(val);
+        signal_change(ctx.conn, ctx.object_path, ctx.name, val.into());
+    }
+    Ok(())
+}
+
+/// Returns the output with the given registry name, which may have been removed while a call
+/// to its object was in flight
+fn output(state: &State, reg_name: u32) -> Result<&Output, MethodError> {
+    state
+        .output_by_reg_name(reg_name)
+        .ok_or_else(unknown_object)
+}
+
+fn unknown_object() -> MethodError {
+    MethodError::new(
+        "org.freedesktop.DBus.Error.UnknownObject",
+        "the object was removed".to_owned(),
+    )
+}
+
+/// Parses the new value of a property
+fn prop_value<'a, T: rustbus::Unmarshal<'a, 'a>>(
+    val: &UnVariant<'a, 'a>,
+) -> Result<T, MethodError> {
+    val.get().map_err(invalid_args)
+}
+
+/// Parses the new value of a gamma or brightness property
+fn finite_prop_value(val: &UnVariant) -> Result<f64, MethodError> {
+    let value: f64 = prop_value(val)?;
+    if !value.is_finite() {
+        return Err(invalid_args("values must be finite"));
+    }
+    Ok(value)
+}
+
+fn invalid_args(err: impl std::fmt::Display) -> MethodError {
+    MethodError::new("org.freedesktop.DBus.Error.InvalidArgs", err.to_string())
+}
+
+/// Night Light is active while the temperature is not neutral, nothing inhibits it and
+/// adjustments are enabled
+fn night_light_active(state: &State) -> bool {
+    state.color().temp != Color::default().temp && !state.is_inhibited() && state.enabled()
+}
+
+/// A property value of a compatibility interface
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CompatValue {
+    Bool(bool),
+    U32(u32),
+}
+
+impl From<bool> for CompatValue {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl From<u32> for CompatValue {
+    fn from(value: u32) -> Self {
+        Self::U32(value)
+    }
+}
+
+impl From<CompatValue> for Param<'static, 'static> {
+    fn from(value: CompatValue) -> Self {
+        match value {
+            CompatValue::Bool(value) => value.into(),
+            CompatValue::U32(value) => value.into(),
+        }
+    }
+}
+
+/// The property values of a compatibility interface, used to signal changes made through
+/// other interfaces
+#[derive(Debug, Default, Clone, PartialEq)]
+struct CompatSnapshot(Vec<(&'static str, CompatValue)>);
+
+impl CompatSnapshot {
+    /// Returns the values as reported by `GetManagedObjects`
+    fn properties(&self) -> HashMap<&'static str, Variant<'static, 'static>> {
+        self.0
+            .iter()
+            .map(|&(name, value)| (name, variant(value.into())))
+            .collect()
+    }
+
+    /// Emits `PropertiesChanged` with the properties that differ from `old`
+    fn signal_changes(&self, conn: &mut DuplexConn, path: &str, iface: &str, old: &Self) {
+        let changed = self
+            .0
+            .iter()
+            .filter(|prop| !old.0.contains(prop))
+            .map(|&(name, value)| (name, Param::from(value)))
+            .collect::<Vec<_>>();
+        if !changed.is_empty() {
+            let sig = prop_changed_message(path, iface, changed, &[]);
+            conn.send.send_message_write_all(&sig).unwrap();
+        }
+    }
+}
+
+fn failed(err: impl std::fmt::Display) -> MethodError {
+    MethodError::new("org.freedesktop.DBus.Error.Failed", format!("{err:#}"))
+}
+
+fn prop_changed_message<'a>(
+    path: &str,
+    iface: &str,
+    changed: impl IntoIterator<Item = (&'a str, Param<'a, 'a>)>,
+    invalidated: &[&str],
+) -> MarshalledMessage {
+    let map = changed
+        .into_iter()
+        .map(|(prop, value)| {
+            let variant = Variant {
+                sig: value.sig(),
+                value,
+            };
+            (prop, variant)
+        })
+        .collect::<HashMap<_, _>>();
 
     let mut sig = MessageBuilder::new()
         .signal("org.freedesktop.DBus.Properties", "PropertiesChanged", path)
         .build();
     sig.body.push_param(iface).unwrap();
     sig.body.push_param(map).unwrap();
-    sig.body.push_param::<&[&str]>(&[]).unwrap();
+    sig.body.push_param(invalidated).unwrap();
     sig
 }
 
 fn signal_change(conn: &mut DuplexConn, path: &str, prop: &str, value: Param) {
-    let output_sig = prop_changed_message(path, "rs.wl.gammarelay", prop, value);
+    let output_sig = prop_changed_message(path, "rs.wl.gammarelay", [(prop, value)], &[]);
     conn.send.send_message_write_all(&output_sig).unwrap();
 }
-
-fn signal_updated_property_to_outputs(
-    conn: &mut DuplexConn,
-    state: &WaylandState,
-    name: &str,
-    value: Param,
-) {
-    for output in state.outputs.iter().filter(|output| output.color_changed()) {
-        if let Some(path) = output.object_path() {
-            signal_change(conn, &path, name, value.clone());
-        }
-    }
-}
@@ -0,0 +1,112 @@
+//! `org.gnome.SettingsDaemon.Color`, for clients made for GNOME's Night Light
+
+use std::time::{Duration, Instant};
+
+use rustbus::wire::unmarshal::traits::Variant as UnVariant;
+use rustbus_service::{Access, InterfaceImp, MethodError, PropContext, Service, rustbus};
+
+use super::{
+    CompatSnapshot, add_parents, check_write, night_light_active, prop_value, sender, set_fields,
+    signal_inhibitors,
+};
+use crate::color::ColorFields;
+use crate::state::{State, Target};
+
+pub const BUS_NAME: &str = "org.gnome.SettingsDaemon.Color";
+pub const PATH: &str = "/org/gnome/SettingsDaemon/Color";
+pub const IFACE: &str = "org.gnome.SettingsDaemon.Color";
+
+/// The values of the properties, used to signal changes made through other interfaces and by
+/// `GetManagedObjects`
+pub fn snapshot(state: &State) -> CompatSnapshot {
+    CompatSnapshot(vec![
+        ("Temperature", u32::from(state.color().temp).into()),
+        ("NightLightActive", night_light_active(state).into()),
+        (
+            "DisabledUntilTomorrow",
+            disabled_until_tomorrow(state).into(),
+        ),
+    ])
+}
+
+pub fn add_object(service: &mut Service<State>) {
+    let iface = InterfaceImp::new(IFACE)
+        .with_prop("NightLightActive", Access::Read(get_night_light_active_cb))
+        .with_prop(
+            "Temperature",
+            Access::ReadWrite(get_temperature_cb, set_temperature_cb),
+        )
+        .with_prop(
+            "DisabledUntilTomorrow",
+            Access::ReadWrite(
+                get_disabled_until_tomorrow_cb,
+                set_disabled_until_tomorrow_cb,
+            ),
+        );
+
+    let mut object = rustbus_service::Object::new();
+    object.add_interface(iface);
+    let (parent, name) = add_parents(service, PATH);
+    let parent = service.get_object_mut(&parent).unwrap();
+    parent.add_child(name, object);
+}
+
+fn disabled_until_tomorrow(state: &State) -> bool {
+    state.inhibitors().iter().any(|i| i.owner == BUS_NAME)
+}
+
+fn get_night_light_active_cb(ctx: PropContext<State>) -> bool {
+    night_light_active(ctx.state)
+}
+
+fn get_temperature_cb(ctx: PropContext<State>) -> u32 {
+    ctx.state.color().temp.into()
+}
+
+fn set_temperature_cb(ctx: PropContext<State>, val: UnVariant) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let temp = prop_value::<u32>(&val)?;
+    let fields = ColorFields {
+        temp: Some(temp.try_into().unwrap_or(u16::MAX)),
+        ..Default::default()
+    };
+    set_fields(ctx.conn, ctx.state, sender(&ctx.msg), Target::Root, fields)
+}
+
+fn get_disabled_until_tomorrow_cb(ctx: PropContext<State>) -> bool {
+    disabled_until_tomorrow(ctx.state)
+}
+
+/// Disabling Night Light inhibits all adjustments until the next local midnight
+fn set_disabled_until_tomorrow_cb(
+    ctx: PropContext<State>,
+    val: UnVariant,
+) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let disabled = prop_value::<bool>(&val)?;
+    if disabled == disabled_until_tomorrow(ctx.state) {
+        return Ok(());
+    }
+    let was_inhibited = ctx.state.is_inhibited();
+    if disabled {
+        let until = Instant::now() + until_midnight();
+        let reason = "Night Light disabled until tomorrow";
+        ctx.state.inhibit_until(BUS_NAME, reason, Some(until));
+    } else {
+        ctx.state.uninhibit_owner(BUS_NAME);
+    }
+    signal_inhibitors(ctx.conn, ctx.state, was_inhibited);
+    Ok(())
+}
+
+/// Returns the time until the next local midnight
+fn until_midnight() -> Duration {
+    // SAFETY: `localtime_r` only writes to `tm`
+    let elapsed = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm = std::mem::zeroed::<libc::tm>();
+        libc::localtime_r(&now, &mut tm);
+        tm.tm_hour * 3600 + tm.tm_min * 60 + tm.tm_sec
+    };
+    Duration::from_secs((86_400 - elapsed).max(1) as u64)
+}
@@ -0,0 +1,97 @@
+//! `org.kde.KWin.NightLight`, for Plasma widgets and scripts made for KWin's Night Light
+
+use rustbus_service::{
+    Access, InterfaceImp, MethodContext, MethodError, PropContext, Service, rustbus,
+};
+
+use super::{
+    CompatSnapshot, add_parents, check_write, night_light_active, sender, signal_inhibitors,
+};
+use crate::color::Color;
+use crate::state::State;
+
+pub const BUS_NAME: &str = "org.kde.KWin.NightLight";
+pub const PATH: &str = "/org/kde/KWin/NightLight";
+pub const IFACE: &str = "org.kde.KWin.NightLight";
+
+/// The values of the properties, used to signal changes made through other interfaces and by
+/// `GetManagedObjects`
+pub fn snapshot(state: &State) -> CompatSnapshot {
+    CompatSnapshot(vec![
+        ("available", true.into()),
+        ("running", night_light_active(state).into()),
+        ("currentTemperature", current_temperature(state).into()),
+        ("targetTemperature", u32::from(state.color().temp).into()),
+    ])
+}
+
+pub fn add_object(service: &mut Service<State>) {
+    let iface = InterfaceImp::new(IFACE)
+        .with_method::<(), u32>("inhibit", inhibit_cb)
+        .with_method::<UnInhibitArgs, ()>("uninhibit", uninhibit_cb)
+        .with_prop("available", Access::Read(get_available_cb))
+        .with_prop("running", Access::Read(get_running_cb))
+        .with_prop(
+            "currentTemperature",
+            Access::Read(get_current_temperature_cb),
+        )
+        .with_prop("targetTemperature", Access::Read(get_target_temperature_cb));
+
+    let mut object = rustbus_service::Object::new();
+    object.add_interface(iface);
+    let (parent, name) = add_parents(service, PATH);
+    let parent = service.get_object_mut(&parent).unwrap();
+    parent.add_child(name, object);
+}
+
+/// The temperature that is displayed, which is neutral while inhibited or disabled
+fn current_temperature(state: &State) -> u32 {
+    if state.is_inhibited() || !state.enabled() {
+        Color::default().temp.into()
+    } else {
+        state.color().temp.into()
+    }
+}
+
+/// Uses the same inhibitors as `rs.wl.gammarelay`, so they are also released when the caller
+/// disconnects
+fn inhibit_cb(ctx: &mut MethodContext<State>, _args: ()) -> Result<u32, MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let was_inhibited = ctx.state.is_inhibited();
+    let cookie = ctx
+        .state
+        .inhibit(sender(&ctx.msg), "org.kde.KWin.NightLight");
+    signal_inhibitors(ctx.conn, ctx.state, was_inhibited);
+    Ok(cookie)
+}
+
+#[derive(rustbus_service::Args)]
+struct UnInhibitArgs {
+    cookie: u32,
+}
+
+/// Like KWin, unknown cookies are ignored
+fn uninhibit_cb(ctx: &mut MethodContext<State>, args: UnInhibitArgs) -> Result<(), MethodError> {
+    check_write(ctx.state, &ctx.msg)?;
+    let was_inhibited = ctx.state.is_inhibited();
+    if ctx.state.uninhibit(args.cookie) {
+        signal_inhibitors(ctx.conn, ctx.state, was_inhibited);
+    }
+    Ok(())
+}
+
+fn get_available_cb(_ctx: PropContext<State>) -> bool {
+    true
+}
+
+fn get_running_cb(ctx: PropContext<State>) -> bool {
+    night_light_active(ctx.state)
+}
+
+fn get_current_temperature_cb(ctx: PropContext<State>) -> u32 {
+    current_temperature(ctx.state)
+}
+
+fn get_target_temperature_cb(ctx: PropContext<State>) -> u32 {
+    ctx.state.color().temp.into()
+}
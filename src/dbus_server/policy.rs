@@ -0,0 +1,137 @@
+//! Which clients may change settings, for machines where untrusted processes share the session
+//! bus
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use rustbus_service::MethodError;
+
+use crate::name_watcher::NameWatcher;
+
+/// Reading is always allowed. Writing is allowed to everyone, unless the policy is restricted,
+/// in which case only the allowed clients may write.
+///
+/// Clients are identified when they connect, from the `NameOwnerChanged` signals, so that
+/// method calls usually do not wait for the bus. A client whose signal has not been handled yet
+/// is looked up when it first tries to write.
+#[derive(Debug, Default)]
+pub struct WritePolicy {
+    restricted: bool,
+    /// Bus names of allowed clients, unique or well-known
+    names: Vec<String>,
+    /// Executables of allowed clients
+    executables: Vec<PathBuf>,
+    /// The current owners of the allowed well-known names
+    owners: HashMap<String, String>,
+    /// Unique names of the clients whose executable is allowed
+    allowed_clients: Vec<String>,
+    /// Unique names of the clients whose executable was looked up
+    identified: HashSet<String>,
+    /// The connection used to look up clients that write before they were identified
+    lookup: Option<NameWatcher>,
+}
+
+impl WritePolicy {
+    /// With `read_only` or a non-empty allowlist, only the clients in `allowed` may write.
+    /// Entries starting with `/` are executables, the others are bus names.
+    pub fn new(read_only: bool, allowed: &[String]) -> Self {
+        let (executables, names): (Vec<_>, Vec<_>) =
+            allowed.iter().partition(|entry| entry.starts_with('/'));
+        Self {
+            restricted: read_only || !allowed.is_empty(),
+            names: names.into_iter().cloned().collect(),
+            // `/proc/<pid>/exe` is resolved, so symlinks in the allowlist are too
+            executables: executables
+                .into_iter()
+                .map(|path| std::fs::canonicalize(path).unwrap_or_else(|_| path.into()))
+                .collect(),
+            owners: HashMap::new(),
+            allowed_clients: Vec::new(),
+            identified: HashSet::new(),
+            lookup: None,
+        }
+    }
+
+    /// Fails with `AccessDenied` unless the client with the unique name `sender` may write
+    pub fn check(&mut self, sender: &str) -> Result<(), MethodError> {
+        if !self.allowed(sender) && self.needs_executables() && !self.identified.contains(sender) {
+            self.identify(sender);
+        }
+        if self.allowed(sender) {
+            return Ok(());
+        }
+        Err(MethodError::new(
+            "org.freedesktop.DBus.Error.AccessDenied",
+            format!("{sender} is not allowed to change settings"),
+        ))
+    }
+
+    fn allowed(&self, sender: &str) -> bool {
+        !self.restricted
+            || self.names.iter().any(|name| name == sender)
+            || self.owners.values().any(|owner| owner == sender)
+            || self.allowed_clients.iter().any(|client| client == sender)
+    }
+
+    /// Looks up a client right away, because the bus has not reported it yet
+    fn identify(&mut self, client: &str) {
+        let Some(lookup) = &mut self.lookup else {
+            return;
+        };
+        match lookup.process_id(client) {
+            Ok(Some(pid)) => self.set_process(client, pid),
+            Ok(None) => (),
+            Err(e) => eprintln!("Could not identify {client}: {e:#}"),
+        }
+    }
+
+    /// Sets the connection used to look up clients that were not identified yet
+    pub fn set_lookup(&mut self, lookup: NameWatcher) {
+        self.lookup = Some(lookup);
+    }
+
+    /// The allowed well-known names, whose owners have to be tracked
+    pub fn well_known_names(&self) -> impl Iterator<Item = &str> {
+        self.names
+            .iter()
+            .filter(|name| self.restricted && !name.starts_with(':'))
+            .map(String::as_str)
+    }
+
+    /// Whether the executables of clients have to be looked up
+    pub fn needs_executables(&self) -> bool {
+        self.restricted && !self.executables.is_empty()
+    }
+
+    /// Records the new owner of a well-known name, empty if it was released
+    pub fn set_owner(&mut self, name: &str, owner: &str) {
+        if !self.names.iter().any(|allowed| allowed == name) {
+            return;
+        }
+        if owner.is_empty() {
+            self.owners.remove(name);
+        } else {
+            self.owners.insert(name.to_owned(), owner.to_owned());
+        }
+    }
+
+    /// Records the process of a client that connected. A unique name always belongs to the same
+    /// process, so a client is only recorded once.
+    pub fn set_process(&mut self, client: &str, pid: u32) {
+        if !self.identified.insert(client.to_owned()) {
+            return;
+        }
+        let Ok(exe) = std::fs::read_link(format!("/proc/{pid}/exe")) else {
+            return;
+        };
+        if self.executables.contains(&exe) {
+            self.allowed_clients.push(client.to_owned());
+        }
+    }
+
+    /// Forgets a client that left the bus
+    pub fn forget(&mut self, client: &str) {
+        self.allowed_clients.retain(|allowed| allowed != client);
+        self.identified.remove(client);
+    }
+}
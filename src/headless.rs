@@ -0,0 +1,127 @@
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::backend::Backend;
+use crate::color::colorramp_fill;
+use crate::state::State;
+
+/// A backend without a display, which only records the ramps it would have applied
+///
+/// Used for testing the daemon without a compositor.
+pub struct Headless {
+    state: State,
+    outputs: Vec<Output>,
+    record: Option<File>,
+}
+
+#[derive(Debug)]
+struct Output {
+    reg_name: u32,
+    name: String,
+    ramp_size: usize,
+}
+
+impl AsRawFd for Headless {
+    fn as_raw_fd(&self) -> RawFd {
+        // There is nothing to wait for, poll() ignores negative fds
+        -1
+    }
+}
+
+impl Headless {
+    /// Creates fake outputs from `NAME[:RAMP_SIZE]` specs. Every applied ramp is appended to
+    /// `record` as a line of the form `NAME R G B`, where each channel is a comma-separated list.
+    pub fn new(outputs: &[String], record: Option<&Path>) -> Result<Self> {
+        let mut state = State::default();
+        let mut headless_outputs = Vec::new();
+
+        let default_outputs = [String::from("HEADLESS-1")];
+        let outputs = if outputs.is_empty() {
+            &default_outputs[..]
+        } else {
+            outputs
+        };
+
+        for (i, spec) in outputs.iter().enumerate() {
+            let (name, ramp_size) = match spec.split_once(':') {
+                Some((name, size)) => (
+                    name,
+                    size.parse()
+                        .with_context(|| format!("invalid ramp size in {spec:?}"))?,
+                ),
+                None => (spec.as_str(), 256),
+            };
+            let reg_name = i as u32 + 1;
+            state.add_output(reg_name);
+            state.set_power_control(reg_name, true);
+            state.set_output_name(reg_name, name.to_owned());
+            headless_outputs.push(Output {
+                reg_name,
+                name: name.to_owned(),
+                ramp_size,
+            });
+        }
+
+        let record = record
+            .map(|path| {
+                File::create(path).with_context(|| format!("could not create {}", path.display()))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            state,
+            outputs: headless_outputs,
+            record,
+        })
+    }
+}
+
+impl Backend for Headless {
+    fn state(&self) -> &State {
+        &self.state
+    }
+
+    fn state_mut(&mut self) -> &mut State {
+        &mut self.state
+    }
+
+    fn poll(&mut self) -> Result<()> {
+        for output in &self.outputs {
+            if let Some(power) = self.state.take_power_request(output.reg_name) {
+                self.state.set_output_power(output.reg_name, power);
+            }
+            if output.ramp_size == 0 {
+                continue;
+            }
+            let Some(color) = self.state.take_color_change(output.reg_name) else {
+                continue;
+            };
+
+            let mut ramp = vec![0; output.ramp_size * 3];
+            let (r, rest) = ramp.split_at_mut(output.ramp_size);
+            let (g, b) = rest.split_at_mut(output.ramp_size);
+            colorramp_fill(r, g, b, output.ramp_size, color);
+
+            if let Some(record) = &mut self.record {
+                let channels = ramp
+                    .chunks(output.ramp_size)
+                    .map(|channel| {
+                        channel
+                            .iter()
+                            .map(u16::to_string)
+                            .collect::<Vec<_>>()
+                            .join(",")
+                    })
+                    .collect::<Vec<_>>();
+                writeln!(record, "{} {}", output.name, channels.join(" "))?;
+                record.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+use crate::color::Color;
+
+/// The number of changes that can be undone
+const HISTORY_LENGTH: usize = 100;
+
+/// Color changes made over DBus, which can be undone and redone
+#[derive(Debug, Default)]
+pub struct History {
+    entries: VecDeque<HistoryEntry>,
+    /// The number of entries that are applied, the rest were undone
+    position: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub time: SystemTime,
+    /// The unique bus name of the client that made the change
+    pub sender: String,
+    /// The object the change was made on
+    pub path: String,
+    /// The color of that object before and after the change
+    pub old: Color,
+    pub new: Color,
+    /// The changed outputs, keyed by name
+    pub outputs: Vec<(String, Color, Color)>,
+}
+
+impl History {
+    /// Records a change, discarding the changes that were undone
+    pub fn record(&mut self, entry: HistoryEntry) {
+        self.entries.truncate(self.position);
+        if self.entries.len() == HISTORY_LENGTH {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+        self.position = self.entries.len();
+    }
+
+    /// Returns the colors that the last applied change replaced
+    pub fn undo(&mut self) -> Option<Vec<(String, Color)>> {
+        self.position = self.position.checked_sub(1)?;
+        let entry = &self.entries[self.position];
+        Some(
+            entry
+                .outputs
+                .iter()
+                .map(|(n, old, _)| (n.clone(), *old))
+                .collect(),
+        )
+    }
+
+    /// Returns the colors of the first undone change
+    pub fn redo(&mut self) -> Option<Vec<(String, Color)>> {
+        let entry = self.entries.get(self.position)?;
+        self.position += 1;
+        Some(
+            entry
+                .outputs
+                .iter()
+                .map(|(n, _, new)| (n.clone(), *new))
+                .collect(),
+        )
+    }
+
+    /// Returns all entries, oldest first, and whether they were undone
+    pub fn entries(&self) -> impl Iterator<Item = (&HistoryEntry, bool)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (entry, i >= self.position))
+    }
+}
@@ -1,74 +1,306 @@
+mod app_rules;
+mod backend;
 mod color;
+mod config;
 mod dbus_client;
 mod dbus_server;
+mod headless;
+mod history;
+mod name_watcher;
+mod output_settings;
+mod presets;
+mod state;
 mod wayland;
+#[cfg(feature = "x11")]
+mod x11;
 
 use std::io;
-use std::os::fd::{AsRawFd, RawFd};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::PathBuf;
+use std::time::Instant;
 
-use clap::{Parser, Subcommand};
-use wayland::WaylandEvent;
+use clap::{Args, Parser, Subcommand};
 
-use color::Color;
+use backend::{Backend, BackendKind};
+use color::Limits;
+use state::{Aggregation, OutputEvent};
 
 #[derive(Debug, Parser)]
 #[clap(author, version, about)]
 struct Cli {
     #[clap(subcommand)]
     command: Option<Command>,
+    /// Bus name of the server, to run several instances on one bus
+    #[clap(long, global = true, value_name = "NAME")]
+    bus_name: Option<String>,
+    /// Suffix the bus name with WAYLAND_DISPLAY, e.g. rs.wl-gammarelay.wayland_1
+    #[clap(long, global = true)]
+    per_display: bool,
+}
+
+impl Cli {
+    /// Returns the bus name used by the server and by clients
+    fn bus_name(&self) -> String {
+        self.bus_name_for(&std::env::var("WAYLAND_DISPLAY").unwrap_or_default())
+    }
+
+    /// Returns the bus name for the given value of `WAYLAND_DISPLAY`
+    fn bus_name_for(&self, display: &str) -> String {
+        let base = self.bus_name.as_deref().unwrap_or("rs.wl-gammarelay");
+        // WAYLAND_DISPLAY may be an absolute path to the socket
+        let display = display.rsplit('/').next().unwrap_or_default();
+        if !self.per_display || display.is_empty() {
+            return base.to_owned();
+        }
+        // Elements of bus names may only contain [A-Za-z0-9_-] and must not start with a digit
+        let mut suffix = display
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect::<String>();
+        if suffix.starts_with(|c: char| c.is_ascii_digit()) {
+            suffix.insert(0, '_');
+        }
+        format!("{base}.{suffix}")
+    }
 }
 
 #[derive(Debug, Subcommand)]
 enum Command {
     /// Run the server
-    Run,
+    Run(RunArgs),
     /// Watch updates
     Watch { format: String },
+    /// Install a DBus service file, so that the server is started when a client needs it
+    InstallDbusService,
+}
+
+#[derive(Debug, Default, Args)]
+struct RunArgs {
+    /// Take over from a running instance, which then exits
+    #[clap(long)]
+    replace: bool,
+    /// Config file, defaults to $XDG_CONFIG_HOME/wl-gammarelay-rs/config.toml
+    #[clap(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Backend used to apply gamma ramps
+    #[clap(long, value_enum, default_value_t)]
+    backend: BackendKind,
+    /// Fake output of the headless backend, may be repeated
+    #[clap(long = "headless-output", value_name = "NAME[:RAMP_SIZE]")]
+    headless_outputs: Vec<String>,
+    /// File to which the headless backend writes every applied ramp
+    #[clap(long, value_name = "PATH")]
+    headless_record: Option<PathBuf>,
+    /// Dim outputs after this many seconds of inactivity, zero disables dimming
+    #[clap(long, value_name = "SECONDS")]
+    idle_dim_timeout: Option<u32>,
+    /// Maximum brightness while dimmed
+    #[clap(long, value_name = "BRIGHTNESS")]
+    idle_dim_brightness: Option<f64>,
+    /// Lowest temperature that can be set
+    #[clap(long, value_name = "KELVIN")]
+    min_temperature: Option<u16>,
+    /// Highest temperature that can be set
+    #[clap(long, value_name = "KELVIN")]
+    max_temperature: Option<u16>,
+    /// Lowest gamma that can be set
+    #[clap(long, value_name = "GAMMA")]
+    min_gamma: Option<f64>,
+    /// Highest gamma that can be set
+    #[clap(long, value_name = "GAMMA")]
+    max_gamma: Option<f64>,
+    /// Lowest brightness that can be set
+    #[clap(long, value_name = "BRIGHTNESS")]
+    min_brightness: Option<f64>,
+    /// Highest brightness that can be set
+    #[clap(long, value_name = "BRIGHTNESS")]
+    max_brightness: Option<f64>,
+    /// How the root color is computed from the colors of the outputs
+    #[clap(long, value_enum)]
+    root_aggregation: Option<Aggregation>,
+    /// Output shown by the root with `--root-aggregation primary`
+    #[clap(long, value_name = "NAME")]
+    primary_output: Option<String>,
+    /// Refuse changes over DBus, except from clients given with `--allow-writer`
+    #[clap(long)]
+    read_only: bool,
+    /// Bus name or executable of a client that may change settings, may be repeated. Other
+    /// clients may only read.
+    #[clap(long = "allow-writer", value_name = "NAME|PATH")]
+    allow_writers: Vec<String>,
+}
+
+impl RunArgs {
+    fn backend(&self) -> anyhow::Result<Box<dyn Backend>> {
+        let config = config::Config::load(self.config.as_deref())?;
+        let limits = self.limits(&config)?;
+
+        let mut backend: Box<dyn Backend> = match self.backend.resolve() {
+            BackendKind::Auto => unreachable!(),
+            BackendKind::Wayland => Box::new(wayland::Wayland::new()?),
+            #[cfg(feature = "x11")]
+            BackendKind::X11 => Box::new(x11::X11::new()?),
+            BackendKind::Headless => Box::new(headless::Headless::new(
+                &self.headless_outputs,
+                self.headless_record.as_deref(),
+            )?),
+        };
+
+        let state = backend.state_mut();
+        state.set_limits(limits);
+        if let Some(aggregation) = self.root_aggregation.or(config.root_aggregation) {
+            state.aggregation = aggregation;
+        }
+        state.primary_output = self.primary_output.clone().or(config.primary_output);
+        if let Some(timeout) = self.idle_dim_timeout.or(config.idle_dim_timeout) {
+            state.idle_dim.timeout = timeout;
+        }
+        if let Some(brightness) = self.idle_dim_brightness.or(config.idle_dim_brightness) {
+            state.idle_dim.brightness = brightness.clamp(0.0, 1.0);
+        }
+        for rule in config.app_rules {
+            state.set_app_rule(rule);
+        }
+        let mut allow_writers = self.allow_writers.clone();
+        allow_writers.extend(config.allow_writers);
+        state.write_policy =
+            dbus_server::WritePolicy::new(self.read_only || config.read_only, &allow_writers);
+        state.presets = presets::Presets::load()?;
+        state.set_output_settings(output_settings::OutputSettings::load()?);
+
+        Ok(backend)
+    }
+
+    /// Merges the limits from the command line and the config file
+    fn limits(&self, config: &config::Config) -> anyhow::Result<Limits> {
+        let default = Limits::default();
+        let limits = Limits {
+            min_temp: self
+                .min_temperature
+                .or(config.min_temperature)
+                .unwrap_or(default.min_temp),
+            max_temp: self
+                .max_temperature
+                .or(config.max_temperature)
+                .unwrap_or(default.max_temp),
+            min_gamma: self
+                .min_gamma
+                .or(config.min_gamma)
+                .unwrap_or(default.min_gamma),
+            max_gamma: self
+                .max_gamma
+                .or(config.max_gamma)
+                .unwrap_or(default.max_gamma),
+            min_brightness: self
+                .min_brightness
+                .or(config.min_brightness)
+                .unwrap_or(default.min_brightness),
+            max_brightness: self
+                .max_brightness
+                .or(config.max_brightness)
+                .unwrap_or(default.max_brightness),
+        };
+        limits
+            .validate()
+            .map_err(|e| anyhow::anyhow!("invalid limits: {e}"))?;
+        Ok(limits)
+    }
 }
 
 fn main() -> anyhow::Result<()> {
-    let command = Cli::parse().command.unwrap_or(Command::Run);
-    match dbus_server::DbusServer::new()? {
+    let cli = Cli::parse();
+    let bus_name = cli.bus_name();
+    let command = cli
+        .command
+        .unwrap_or_else(|| Command::Run(RunArgs::default()));
+    let replace = match &command {
+        Command::Run(args) => args.replace,
+        Command::Watch { .. } => false,
+        Command::InstallDbusService => return install_dbus_service(&bus_name),
+    };
+    match dbus_server::DbusServer::new(&bus_name, replace)? {
         Some(mut dbus_server) => {
-            let mut wayland = wayland::Wayland::new()?;
+            let mut backend = match &command {
+                Command::Run(args) => args.backend()?,
+                Command::Watch { .. } => RunArgs::default().backend()?,
+                Command::InstallDbusService => unreachable!(),
+            };
+            dbus_server.watch_writers(backend.state_mut())?;
             let mut dbus_client = match command {
-                Command::Run => None,
-                Command::Watch { format } => Some(dbus_client::DbusClient::new(format, false)?),
+                Command::Run(_) => None,
+                Command::Watch { format } => {
+                    Some(dbus_client::DbusClient::new(format, &bus_name, false)?)
+                }
+                Command::InstallDbusService => unreachable!(),
             };
+            let signals = termination_signals()?;
             let mut fds = [
                 pollin(dbus_server.as_raw_fd()),
-                pollin(wayland.as_raw_fd()),
+                pollin(backend.as_raw_fd()),
+                pollin(dbus_server.name_watcher_fd()),
+                pollin(signals.as_raw_fd()),
                 pollin(dbus_client.as_ref().map_or(-1, |x| x.as_raw_fd())),
             ];
-            let fds_cnt = if dbus_client.is_some() { 3 } else { 2 };
+            let fds_cnt = if dbus_client.is_some() { 5 } else { 4 };
             loop {
-                while let Some(event) = wayland.next_event() {
+                let mut outputs_changed = false;
+                while let Some(event) = backend.state_mut().next_event() {
                     match event {
-                        WaylandEvent::NewOutput { reg_name, name } => {
-                            dbus_server.add_output(reg_name, &name);
+                        OutputEvent::NewOutput { reg_name, name } => {
+                            dbus_server.add_output(backend.state(), reg_name, &name);
+                            outputs_changed = true;
                         }
-                        WaylandEvent::RemoveOutput { name } => {
+                        OutputEvent::RemoveOutput { name } => {
                             dbus_server.remove_output(&name);
+                            outputs_changed = true;
+                        }
+                        OutputEvent::PowerChanged { name, power } => {
+                            dbus_server.power_changed(&name, power);
+                        }
+                        OutputEvent::NewGroup { id } => {
+                            dbus_server.add_group(backend.state(), id);
+                            outputs_changed = true;
+                        }
+                        OutputEvent::RemoveGroup { path } => {
+                            dbus_server.remove_group(&path);
+                            outputs_changed = true;
                         }
                     }
                 }
+                if outputs_changed {
+                    dbus_server.update_aggregates(backend.state());
+                }
 
-                poll(&mut fds[..fds_cnt])?;
-                if fds[0].revents != 0 {
-                    dbus_server.poll(&mut wayland.state)?;
+                poll(&mut fds[..fds_cnt], backend.state().next_deadline())?;
+                // Exiting normally drops the backend, which restores gamma where needed
+                if fds[3].revents != 0 {
+                    eprintln!("Terminated, exiting");
+                    break;
                 }
-                if fds[1].revents != 0 || wayland.state.color_changed() {
-                    wayland.poll()?;
+                dbus_server.expire_timers(backend.state_mut());
+                if fds[0].revents != 0 {
+                    dbus_server.poll(backend.state_mut())?;
                 }
                 if fds[2].revents != 0 {
+                    dbus_server.poll_name_watcher(backend.state_mut())?;
+                }
+                // Name changes are also handled while polling the service connection
+                if dbus_server.name_lost() {
+                    eprintln!("Replaced by another instance, exiting");
+                    break;
+                }
+                // Settings changed over DBus may need to be handled by the backend too
+                backend.poll()?;
+                if fds[4].revents != 0 {
                     dbus_client.as_mut().unwrap().run(false)?;
                 }
             }
         }
         None => match command {
-            Command::Run => eprintln!("wl-gammarelay-rs is already running"),
+            Command::Run(_) => eprintln!("wl-gammarelay-rs is already running"),
+            Command::InstallDbusService => unreachable!(),
             Command::Watch { format } => {
-                let mut dbus_client = dbus_client::DbusClient::new(format, true)?;
+                let mut dbus_client = dbus_client::DbusClient::new(format, &bus_name, true)?;
                 dbus_client.run(true)?;
             }
         },
@@ -77,130 +309,57 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-impl wayland::WaylandState {
-    pub fn output_by_reg_name(&self, reg_name: u32) -> Option<&wayland::Output> {
-        self.outputs
-            .iter()
-            .find(|output| output.reg_name() == reg_name)
-    }
-
-    pub fn mut_output_by_reg_name(&mut self, reg_name: u32) -> Option<&mut wayland::Output> {
-        self.outputs
-            .iter_mut()
-            .find(|output| output.reg_name() == reg_name)
-    }
+/// Writes `<bus_name>.service`, which starts `run` with the current executable
+fn install_dbus_service(bus_name: &str) -> anyhow::Result<()> {
+    use anyhow::Context;
 
-    /// Returns the average color of all outputs, or the default color if there are no outputs
-    pub fn color(&self) -> Color {
-        if self.outputs.is_empty() {
-            Color::default()
-        } else {
-            let color = self.outputs.iter().fold(
-                Color {
-                    inverted: true,
-                    brightness: 0.0,
-                    temp: 0,
-                    gamma: 0.0,
-                },
-                |color, output| {
-                    let output_color = output.color();
-                    Color {
-                        inverted: color.inverted && output_color.inverted,
-                        brightness: color.brightness + output_color.brightness,
-                        temp: color.temp + output_color.temp,
-                        gamma: color.gamma + output_color.gamma,
-                    }
-                },
-            );
-
-            Color {
-                temp: color.temp / self.outputs.len() as u16,
-                gamma: color.gamma / self.outputs.len() as f64,
-                brightness: color.brightness / self.outputs.len() as f64,
-                inverted: color.inverted,
-            }
-        }
-    }
-
-    pub fn color_changed(&self) -> bool {
-        self.outputs.iter().any(|output| output.color_changed())
-    }
-
-    pub fn set_inverted(&mut self, inverted: bool) {
-        for output in &mut self.outputs {
-            let color = output.color();
-            output.set_color(Color { inverted, ..color });
-        }
-    }
-
-    pub fn set_brightness(&mut self, brightness: f64) {
-        for output in &mut self.outputs {
-            let color = output.color();
-            output.set_color(Color {
-                brightness,
-                ..color
-            });
-        }
-    }
-
-    /// Returns `true` if any output was updated
-    pub fn update_brightness(&mut self, delta: f64) -> bool {
-        let mut updated = false;
-        for output in &mut self.outputs {
-            let color = output.color();
-            let brightness = (color.brightness + delta).clamp(0.0, 1.0);
-            if brightness != color.brightness {
-                updated = true;
-                output.set_color(Color {
-                    brightness,
-                    ..color
-                });
-            }
-        }
-
-        updated
-    }
-
-    pub fn set_temperature(&mut self, temp: u16) {
-        for output in &mut self.outputs {
-            let color = output.color();
-            output.set_color(Color { temp, ..color });
-        }
-    }
+    let dir = config::dbus_services_dir().context("could not find the data directory")?;
+    std::fs::create_dir_all(&dir).with_context(|| format!("could not create {}", dir.display()))?;
+    let exe = std::env::current_exe().context("could not find the executable")?;
+    let path = dir.join(format!("{bus_name}.service"));
+    let exe = exe
+        .to_str()
+        .context("the executable path is not valid UTF-8")?;
+    let service = format!(
+        "[D-BUS Service]\nName={bus_name}\nExec={} --bus-name {bus_name} run\n",
+        quote_exec_arg(exe)
+    );
+    std::fs::write(&path, service)
+        .with_context(|| format!("could not write {}", path.display()))?;
+    println!("Installed {}", path.display());
+    Ok(())
+}
 
-    /// Returns `true` if any output was updated
-    pub fn update_temperature(&mut self, delta: i16) -> bool {
-        let mut updated = false;
-        for output in &mut self.outputs {
-            if let Some(new_color) = output.color().with_updated_temp(delta) {
-                updated = true;
-                output.set_color(new_color);
-            }
+/// Quotes an argument of an `Exec=` line, which the bus splits like a shell would
+fn quote_exec_arg(arg: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in arg.chars() {
+        if matches!(c, '"' | '\\' | '`' | '$') {
+            quoted.push('\\');
         }
-
-        updated
+        quoted.push(c);
     }
+    quoted.push('"');
+    quoted
+}
 
-    pub fn set_gamma(&mut self, gamma: f64) {
-        for output in &mut self.outputs {
-            let color = output.color();
-            output.set_color(Color { gamma, ..color });
+/// Blocks SIGINT and SIGTERM, and returns a file descriptor that becomes readable when one of
+/// them is received instead
+fn termination_signals() -> io::Result<OwnedFd> {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        let err = libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        if err != 0 {
+            return Err(io::Error::from_raw_os_error(err));
         }
-    }
-
-    /// Returns `true` if any output was updated
-    pub fn update_gamma(&mut self, delta: f64) -> bool {
-        let mut updated = false;
-        for output in &mut self.outputs {
-            let color = output.color();
-            let gamma = (output.color().gamma + delta).max(0.1);
-            if gamma != color.gamma {
-                updated = true;
-                output.set_color(Color { gamma, ..color });
-            }
+        let fd = libc::signalfd(-1, &set, libc::SFD_CLOEXEC);
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
         }
-
-        updated
+        Ok(OwnedFd::from_raw_fd(fd))
     }
 }
 
@@ -212,9 +371,15 @@ fn pollin(fd: RawFd) -> libc::pollfd {
     }
 }
 
-fn poll(fds: &mut [libc::pollfd]) -> io::Result<()> {
+/// Waits until any of `fds` is ready, or until `deadline`
+fn poll(fds: &mut [libc::pollfd], deadline: Option<Instant>) -> io::Result<()> {
     loop {
-        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, -1) } == -1 {
+        let timeout = deadline.map_or(-1, |deadline| {
+            // Round up, so that the deadline has passed when poll returns
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            timeout.as_millis().saturating_add(1).min(i32::MAX as u128) as i32
+        });
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, timeout) } == -1 {
             let err = io::Error::last_os_error();
             if err.kind() == io::ErrorKind::Interrupted {
                 continue;
@@ -224,3 +389,36 @@ fn poll(fds: &mut [libc::pollfd]) -> io::Result<()> {
         return Ok(());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::parse_from(std::iter::once("wl-gammarelay-rs").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn bus_name_per_display() {
+        let per_display = cli(&["--per-display"]);
+        assert_eq!(
+            per_display.bus_name_for("wayland-1"),
+            "rs.wl-gammarelay.wayland_1"
+        );
+        assert_eq!(
+            per_display.bus_name_for("wayland.0:x"),
+            "rs.wl-gammarelay.wayland_0_x"
+        );
+        assert_eq!(
+            per_display.bus_name_for("/run/user/1000/wayland-0"),
+            "rs.wl-gammarelay.wayland_0"
+        );
+        assert_eq!(per_display.bus_name_for("0"), "rs.wl-gammarelay._0");
+        assert_eq!(per_display.bus_name_for(""), "rs.wl-gammarelay");
+        assert_eq!(
+            cli(&["--per-display", "--bus-name", "org.example.Gamma"]).bus_name_for("wayland-1"),
+            "org.example.Gamma.wayland_1"
+        );
+        assert_eq!(cli(&[]).bus_name_for("wayland-1"), "rs.wl-gammarelay");
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::VecDeque;
+use std::os::fd::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, bail};
+use rustbus_service::rustbus::{
+    self, DuplexConn, MessageBuilder, MessageType, connection::Timeout, get_session_bus_path,
+    message_builder::MarshalledMessage, standard_messages,
+};
+
+/// How long to wait for the bus to reply to [`NameWatcher::call_bus`]
+const CALL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A separate bus connection that reports clients disconnecting from the bus, and changes of
+/// the owner of the service name and of the watched names. It also looks up clients.
+///
+/// The service connection only handles method calls, so signals are received here.
+pub struct NameWatcher {
+    conn: DuplexConn,
+    /// Changes received while waiting for a reply
+    pending: VecDeque<NameChange>,
+}
+
+/// A `NameOwnerChanged` signal
+#[derive(Debug)]
+pub struct NameChange {
+    pub name: String,
+    /// The unique name of the new owner, empty if the name was released
+    pub new_owner: String,
+}
+
+impl AsRawFd for NameWatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.conn.as_raw_fd()
+    }
+}
+
+impl std::fmt::Debug for NameWatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NameWatcher")
+            .field("pending", &self.pending)
+            .finish_non_exhaustive()
+    }
+}
+
+impl NameWatcher {
+    pub fn new(bus_name: &str) -> Result<Self> {
+        let mut this = Self::connect()?;
+        // arg2 is the new owner, which is empty when a name is released
+        this.conn
+            .send
+            .send_message_write_all(&standard_messages::add_match(
+                "type='signal',sender='org.freedesktop.DBus',interface='org.freedesktop.DBus',member='NameOwnerChanged',arg2=''",
+            ))?;
+        // Another instance replacing this one
+        this.watch_name(bus_name)?;
+        Ok(this)
+    }
+
+    /// Connects without watching any name, for looking up clients
+    pub fn connect() -> Result<Self> {
+        let mut conn = DuplexConn::connect_to_bus(get_session_bus_path()?, true)?;
+        conn.send_hello(Timeout::Infinite)?;
+        Ok(Self {
+            conn,
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Also reports every change of the owner of `name`
+    pub fn watch_name(&mut self, name: &str) -> Result<()> {
+        self.add_match(&format!("arg0='{name}'"))
+    }
+
+    /// Also reports clients connecting to the bus, and names being acquired
+    pub fn watch_new_names(&mut self) -> Result<()> {
+        // arg1 is the old owner, which is empty when a name is acquired
+        self.add_match("arg1=''")
+    }
+
+    fn add_match(&mut self, args: &str) -> Result<()> {
+        self.conn
+            .send
+            .send_message_write_all(&standard_messages::add_match(&format!(
+                "type='signal',sender='org.freedesktop.DBus',interface='org.freedesktop.DBus',member='NameOwnerChanged',{args}",
+            )))?;
+        Ok(())
+    }
+
+    /// Calls a method of the bus, with an optional name as argument, and waits for the reply.
+    /// Returns `None` if the bus replies with an error, e.g. because the name has no owner.
+    pub fn call_bus(
+        &mut self,
+        member: &str,
+        name: Option<&str>,
+    ) -> Result<Option<MarshalledMessage>> {
+        let mut msg = MessageBuilder::new()
+            .call(member)
+            .on("/org/freedesktop/DBus")
+            .with_interface("org.freedesktop.DBus")
+            .at("org.freedesktop.DBus")
+            .build();
+        if let Some(name) = name {
+            msg.body.push_param(name)?;
+        }
+        let serial = self.conn.send.send_message_write_all(&msg)?;
+        let deadline = Instant::now() + CALL_TIMEOUT;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                bail!("no reply to {member}");
+            }
+            let msg = match self
+                .conn
+                .recv
+                .get_next_message(Timeout::Duration(remaining))
+            {
+                Ok(msg) => msg,
+                Err(rustbus::connection::Error::TimedOut) => bail!("no reply to {member}"),
+                Err(e) => return Err(e.into()),
+            };
+            if msg.dynheader.response_serial == Some(serial) {
+                return Ok((msg.typ != MessageType::Error).then_some(msg));
+            }
+            if let Some(change) = name_change(&msg)? {
+                self.pending.push_back(change);
+            }
+        }
+    }
+
+    /// Returns the owner changes since the last call, without blocking
+    pub fn poll(&mut self) -> Result<Vec<NameChange>> {
+        let mut changes = Vec::new();
+        while let Some(change) = self.next_change(Timeout::Nonblock)? {
+            changes.push(change);
+        }
+        Ok(changes)
+    }
+
+    /// Waits until the connection with the unique name `name` leaves the bus. Returns `false`
+    /// on timeout.
+    pub fn wait_until_vanished(&mut self, name: &str, timeout: Duration) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(false);
+            }
+            match self.next_change(Timeout::Duration(remaining))? {
+                Some(change) if change.name == name && change.new_owner.is_empty() => {
+                    return Ok(true);
+                }
+                Some(_) => (),
+                None => return Ok(false),
+            }
+        }
+    }
+
+    /// Returns the process ID of the client with the unique name `name`, or `None` if it left
+    pub fn process_id(&mut self, name: &str) -> Result<Option<u32>> {
+        match self.call_bus("GetConnectionUnixProcessID", Some(name))? {
+            Some(reply) => Ok(Some(reply.body.parser().get::<u32>()?)),
+            None => Ok(None),
+        }
+    }
+
+    fn next_change(&mut self, timeout: Timeout) -> Result<Option<NameChange>> {
+        if let Some(change) = self.pending.pop_front() {
+            return Ok(Some(change));
+        }
+        loop {
+            let msg = match self.conn.recv.get_next_message(timeout) {
+                Ok(msg) => msg,
+                Err(rustbus::connection::Error::TimedOut) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(change) = name_change(&msg)? {
+                return Ok(Some(change));
+            }
+        }
+    }
+}
+
+fn name_change(msg: &MarshalledMessage) -> Result<Option<NameChange>> {
+    if msg.typ != MessageType::Signal || msg.dynheader.member.as_deref() != Some("NameOwnerChanged")
+    {
+        return Ok(None);
+    }
+    let mut parser = msg.body.parser();
+    let name = parser.get::<String>()?;
+    let _old_owner = parser.get::<String>()?;
+    let new_owner = parser.get::<String>()?;
+    Ok(Some(NameChange { name, new_owner }))
+}
@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::color::Calibration;
+
+/// Settings of individual outputs, saved in `$XDG_DATA_HOME/wl-gammarelay-rs/outputs.toml`
+#[derive(Debug, Default)]
+pub struct OutputSettings {
+    path: Option<PathBuf>,
+    outputs: BTreeMap<String, OutputSetting>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutputSetting {
+    /// Whether the daemon controls the gamma of the output
+    pub managed: bool,
+    #[serde(flatten)]
+    pub calibration: Calibration,
+}
+
+impl Default for OutputSetting {
+    fn default() -> Self {
+        Self {
+            managed: true,
+            calibration: Calibration::default(),
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct OutputSettingsFile {
+    /// Settings keyed by output name
+    #[serde(default, rename = "output")]
+    outputs: BTreeMap<String, OutputSetting>,
+}
+
+impl OutputSettings {
+    /// Loads the settings from the data file, if it exists
+    pub fn load() -> Result<Self> {
+        let Some(path) = crate::config::data_dir().map(|dir| dir.join("outputs.toml")) else {
+            return Ok(Self::default());
+        };
+        let outputs = crate::config::load_data::<OutputSettingsFile>(&path)?.outputs;
+        for (name, setting) in &outputs {
+            setting.calibration.validate().map_err(|e| {
+                anyhow::anyhow!("invalid calibration of {name:?} in {}: {e}", path.display())
+            })?;
+        }
+        Ok(Self {
+            path: Some(path),
+            outputs,
+        })
+    }
+
+    fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let file = OutputSettingsFile {
+            outputs: self.outputs.clone(),
+        };
+        crate::config::save_data(path, &file)
+    }
+
+    /// Returns the settings of the output with this name
+    pub fn get(&self, name: &str) -> OutputSetting {
+        self.outputs.get(name).copied().unwrap_or_default()
+    }
+
+    /// Replaces the settings of an output. Outputs with default settings are not saved.
+    pub fn set(&mut self, name: &str, setting: OutputSetting) -> Result<()> {
+        if setting == OutputSetting::default() {
+            self.outputs.remove(name);
+        } else {
+            self.outputs.insert(name.to_owned(), setting);
+        }
+        self.save()
+    }
+}
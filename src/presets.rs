@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::color::Color;
+use crate::state::State;
+
+/// Named sets of colors, saved in `$XDG_DATA_HOME/wl-gammarelay-rs/presets.toml`
+#[derive(Debug, Default)]
+pub struct Presets {
+    path: Option<PathBuf>,
+    presets: Vec<Preset>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Preset {
+    pub name: String,
+    /// Colors keyed by output name
+    pub outputs: BTreeMap<String, Color>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct PresetsFile {
+    #[serde(default, rename = "preset")]
+    presets: Vec<Preset>,
+}
+
+impl Presets {
+    /// Loads the presets from the data file, if it exists
+    pub fn load() -> Result<Self> {
+        let Some(path) = crate::config::data_dir().map(|dir| dir.join("presets.toml")) else {
+            return Ok(Self::default());
+        };
+        let presets = crate::config::load_data::<PresetsFile>(&path)?.presets;
+        Ok(Self {
+            path: Some(path),
+            presets,
+        })
+    }
+
+    fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let file = PresetsFile {
+            presets: self.presets.clone(),
+        };
+        crate::config::save_data(path, &file)
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.presets.iter().map(|p| p.name.clone()).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Preset> {
+        self.presets.iter().find(|p| p.name == name)
+    }
+
+    /// Adds a preset, or replaces the preset with the same name
+    pub fn insert(&mut self, preset: Preset) -> Result<()> {
+        match self.presets.iter_mut().find(|p| p.name == preset.name) {
+            Some(old) => *old = preset,
+            None => self.presets.push(preset),
+        }
+        self.save()
+    }
+
+    /// Returns `false` if there is no preset with this name
+    pub fn remove(&mut self, name: &str) -> Result<bool> {
+        let len = self.presets.len();
+        self.presets.retain(|p| p.name != name);
+        if self.presets.len() == len {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Returns the preset after (or before) `current`, wrapping around
+    pub fn cycle(&self, current: Option<&str>, forward: bool) -> Option<&Preset> {
+        let len = self.presets.len();
+        if len == 0 {
+            return None;
+        }
+        let index = match current.and_then(|name| self.presets.iter().position(|p| p.name == name))
+        {
+            Some(i) if forward => (i + 1) % len,
+            Some(i) => (i + len - 1) % len,
+            None if forward => 0,
+            None => len - 1,
+        };
+        Some(&self.presets[index])
+    }
+
+    /// Returns the first preset whose colors match the colors of all named outputs it has
+    /// colors for
+    pub fn active(&self, state: &State) -> Option<&Preset> {
+        self.presets.iter().find(|preset| {
+            let mut matched = false;
+            for output in &state.outputs {
+                if let Some(color) = output.name().and_then(|name| preset.outputs.get(name)) {
+                    if *color != output.color() {
+                        return false;
+                    }
+                    matched = true;
+                }
+            }
+            matched
+        })
+    }
+}
+
+impl Preset {
+    /// Captures the colors of all named outputs
+    pub fn capture(name: &str, state: &State) -> Self {
+        Self {
+            name: name.to_owned(),
+            outputs: state
+                .outputs
+                .iter()
+                .filter_map(|output| Some((output.name()?.to_owned(), output.color())))
+                .collect(),
+        }
+    }
+}
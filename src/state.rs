@@ -0,0 +1,1064 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::app_rules::{AppRule, Toplevel};
+use crate::color::{Calibration, Color, ColorDeltas, ColorFields, Limits};
+use crate::dbus_server::WritePolicy;
+use crate::history::History;
+use crate::output_settings::{OutputSetting, OutputSettings};
+use crate::presets::Presets;
+
+/// Backend-independent state, shared between the gamma backend and the DBus server
+#[derive(Debug, Default)]
+pub struct State {
+    pub outputs: Vec<Output>,
+    pub events: VecDeque<OutputEvent>,
+    pub idle_dim: IdleDim,
+    pub limits: Limits,
+    pub aggregation: Aggregation,
+    /// The output shown by [`Aggregation::Primary`], the first output if unset or disconnected
+    pub primary_output: Option<String>,
+    pub presets: Presets,
+    pub history: History,
+    /// Which DBus clients may change settings
+    pub write_policy: WritePolicy,
+    output_settings: OutputSettings,
+    idle: bool,
+    /// Whether all adjustments are switched off
+    disabled: bool,
+    inhibitors: Vec<Inhibitor>,
+    next_inhibit_cookie: u32,
+    groups: Vec<Group>,
+    next_group_id: u32,
+    app_rules: Vec<AppRule>,
+    toplevels: Vec<Toplevel>,
+    /// Index of the first app rule matching one of the toplevels
+    active_app_rule: Option<usize>,
+}
+
+/// Dimming of all outputs while the user is idle
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IdleDim {
+    /// Seconds of inactivity after which outputs are dimmed, or zero to disable dimming
+    pub timeout: u32,
+    /// The maximum brightness while dimmed
+    pub brightness: f64,
+}
+
+impl Default for IdleDim {
+    fn default() -> Self {
+        Self {
+            timeout: 0,
+            brightness: 0.5,
+        }
+    }
+}
+
+/// How the color of the root and of groups is computed from the colors of their outputs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    /// The mean of each parameter, inverted if all outputs are
+    #[default]
+    Mean,
+    /// The lowest of each parameter, inverted if all outputs are
+    Min,
+    /// The highest of each parameter, inverted if any output is
+    Max,
+    /// The color of the primary output
+    Primary,
+}
+
+impl Aggregation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Mean => "mean",
+            Self::Min => "min",
+            Self::Max => "max",
+            Self::Primary => "primary",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Self::Mean, Self::Min, Self::Max, Self::Primary]
+            .into_iter()
+            .find(|aggregation| aggregation.as_str() == name)
+    }
+}
+
+/// A client that requested neutral colors
+#[derive(Debug, Clone)]
+pub struct Inhibitor {
+    pub cookie: u32,
+    /// The unique bus name of the client
+    pub owner: String,
+    pub reason: String,
+    /// When the inhibitor is released automatically
+    pub until: Option<Instant>,
+}
+
+/// A set of outputs that can be changed together, by their names
+#[derive(Debug, Clone)]
+pub struct Group {
+    pub id: u32,
+    pub name: String,
+    pub outputs: Vec<String>,
+}
+
+impl Group {
+    pub fn object_path(&self) -> String {
+        format!("/groups/{}", object_name(&self.name))
+    }
+}
+
+/// The object a DBus call was made on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// All managed outputs
+    Root,
+    /// An output, by its registry name
+    Output(u32),
+    /// The connected, managed outputs of a group, by its id
+    Group(u32),
+}
+
+#[derive(Debug)]
+pub enum OutputEvent {
+    NewOutput { reg_name: u32, name: String },
+    RemoveOutput { name: String },
+    PowerChanged { name: String, power: bool },
+    NewGroup { id: u32 },
+    RemoveGroup { path: String },
+}
+
+#[derive(Debug)]
+pub struct Output {
+    reg_name: u32,
+    name: Option<String>,
+    /// Whether the name was made up by the backend, so it may differ in the next session
+    synthesized_name: bool,
+    /// The make and model of the monitor, if the backend knows them
+    identity: Option<String>,
+    color: Color,
+    color_changed: bool,
+    power: bool,
+    /// Whether the backend can turn the output on or off
+    power_control: bool,
+    power_request: Option<bool>,
+    temporary: Option<Temporary>,
+    /// `false` if the daemon must leave the gamma of the output alone
+    managed: bool,
+    calibration: Calibration,
+    /// Whether the adjustments of this output are switched off
+    disabled: bool,
+    link: Option<Link>,
+}
+
+/// Makes an output copy the color of another one
+#[derive(Debug, Clone)]
+pub struct Link {
+    /// The name of the source output
+    pub source: String,
+    /// Applied to the color of the source
+    pub offsets: ColorDeltas,
+}
+
+/// Values displayed instead of the output's color until a deadline
+#[derive(Debug, Clone, Copy)]
+struct Temporary {
+    fields: ColorFields,
+    until: Instant,
+}
+
+impl Output {
+    pub fn reg_name(&self) -> u32 {
+        self.reg_name
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn color(&self) -> Color {
+        self.color
+    }
+
+    pub fn color_changed(&self) -> bool {
+        self.color_changed
+    }
+
+    pub fn set_color(&mut self, color: Color) {
+        if color != self.color {
+            self.color = color;
+            self.color_changed = true;
+        }
+    }
+
+    pub fn managed(&self) -> bool {
+        self.managed
+    }
+
+    pub fn calibration(&self) -> Calibration {
+        self.calibration
+    }
+
+    pub fn link(&self) -> Option<&Link> {
+        self.link.as_ref()
+    }
+
+    /// Returns `false` if the adjustments of this output are switched off. Its color is kept.
+    pub fn enabled(&self) -> bool {
+        !self.disabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if self.disabled == enabled {
+            self.disabled = !enabled;
+            self.color_changed = true;
+        }
+    }
+
+    /// Returns `false` if the backend reported that the output is turned off
+    pub fn power(&self) -> bool {
+        self.power
+    }
+
+    pub fn power_control(&self) -> bool {
+        self.power_control
+    }
+
+    /// Asks the backend to turn the output on or off
+    pub fn request_power(&mut self, power: bool) {
+        self.power_request = Some(power);
+    }
+
+    /// Returns the time until the temporary color is reverted, or zero if there is none
+    pub fn temporary_remaining(&self) -> Duration {
+        self.temporary.map_or(Duration::ZERO, |t| {
+            t.until.saturating_duration_since(Instant::now())
+        })
+    }
+
+    /// The key of the saved settings. Monitors that identify themselves keep their settings only
+    /// on the same connector, so that another monitor plugged in there, or an identical one
+    /// elsewhere, does not get them. Outputs with a made-up name have no key, unless the monitor
+    /// identifies itself.
+    fn settings_key(&self) -> Option<String> {
+        let name = self.name.as_deref().filter(|_| !self.synthesized_name);
+        match (&self.identity, name) {
+            (Some(identity), Some(name)) => Some(format!("{identity} on {name}")),
+            (Some(identity), None) => Some(identity.clone()),
+            (None, name) => name.map(str::to_owned),
+        }
+    }
+
+    fn setting(&self) -> OutputSetting {
+        OutputSetting {
+            managed: self.managed,
+            calibration: self.calibration,
+        }
+    }
+
+    fn apply_setting(&mut self, setting: OutputSetting) {
+        if self.managed != setting.managed {
+            eprintln!("Output {}: managed = {}", self.reg_name, setting.managed);
+            self.managed = setting.managed;
+            self.color_changed = true;
+        }
+        if self.calibration != setting.calibration {
+            self.calibration = setting.calibration;
+            self.color_changed = true;
+        }
+    }
+
+    pub fn object_path(&self) -> Option<String> {
+        self.name
+            .as_deref()
+            .map(|name| format!("/outputs/{}", object_name(name)))
+    }
+}
+
+/// Combines the colors of `outputs` into one, or returns the default color if there are none
+fn aggregate_color<'a>(
+    outputs: impl IntoIterator<Item = &'a Output>,
+    aggregation: Aggregation,
+    primary: Option<&str>,
+) -> Color {
+    let outputs = outputs.into_iter().collect::<Vec<_>>();
+    let mut colors = outputs.iter().map(|output| output.color());
+    let Some(first) = outputs.first() else {
+        return Color::default();
+    };
+    match aggregation {
+        Aggregation::Mean => {
+            // Summed in wider types, since many outputs at 10000K overflow a u16
+            let count = outputs.len() as u32;
+            let temp = colors.clone().map(|c| u32::from(c.temp)).sum::<u32>() / count;
+            Color {
+                temp: temp as u16,
+                gamma: colors.clone().map(|c| c.gamma).sum::<f64>() / count as f64,
+                brightness: colors.clone().map(|c| c.brightness).sum::<f64>() / count as f64,
+                inverted: colors.all(|c| c.inverted),
+            }
+        }
+        Aggregation::Min => colors
+            .reduce(|a, b| Color {
+                temp: a.temp.min(b.temp),
+                gamma: a.gamma.min(b.gamma),
+                brightness: a.brightness.min(b.brightness),
+                inverted: a.inverted && b.inverted,
+            })
+            .unwrap(),
+        Aggregation::Max => colors
+            .reduce(|a, b| Color {
+                temp: a.temp.max(b.temp),
+                gamma: a.gamma.max(b.gamma),
+                brightness: a.brightness.max(b.brightness),
+                inverted: a.inverted || b.inverted,
+            })
+            .unwrap(),
+        Aggregation::Primary => outputs
+            .iter()
+            .find(|output| primary.is_some_and(|primary| output.name() == Some(primary)))
+            .unwrap_or(first)
+            .color(),
+    }
+}
+
+/// Returns the name of the DBus object of an output. Characters that are not allowed in object
+/// paths, like the `-` in `HDMI-A-1`, are replaced with `_`.
+pub fn object_name(output_name: &str) -> String {
+    if output_name.is_empty() {
+        return "_".to_owned();
+    }
+    output_name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+impl State {
+    /// Adds a new unnamed output. The output starts with the current average color, so newly
+    /// connected outputs match the existing ones.
+    pub fn add_output(&mut self, reg_name: u32) -> &mut Output {
+        let color = self.limits.clamp(self.color());
+        self.outputs.push(Output {
+            reg_name,
+            name: None,
+            synthesized_name: false,
+            identity: None,
+            color,
+            color_changed: true,
+            power: true,
+            power_control: false,
+            power_request: None,
+            temporary: None,
+            managed: true,
+            calibration: Calibration::default(),
+            disabled: false,
+            link: None,
+        });
+        self.outputs.last_mut().unwrap()
+    }
+
+    /// Installs new limits and clamps the colors of the existing outputs to them
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        for output in &mut self.outputs {
+            output.set_color(limits.clamp(output.color));
+        }
+    }
+
+    /// Assigns a name to an output, which makes it visible on DBus and applies its settings
+    pub fn set_output_name(&mut self, reg_name: u32, name: String) {
+        self.name_output(reg_name, name, false);
+    }
+
+    /// Like [`State::set_output_name`], for a name made up by the backend. Unless the monitor
+    /// identifies itself, its settings are not saved, since the name may belong to another
+    /// output in the next session.
+    pub fn set_synthesized_output_name(&mut self, reg_name: u32, name: String) {
+        self.name_output(reg_name, name, true);
+    }
+
+    fn name_output(&mut self, reg_name: u32, name: String, synthesized: bool) {
+        let Some(output) = self.mut_output_by_reg_name(reg_name) else {
+            return;
+        };
+        eprintln!("Output {reg_name}: name = {name:?}");
+        output.name = Some(name.clone());
+        output.synthesized_name = synthesized;
+        self.apply_saved_setting(reg_name);
+        self.events
+            .push_back(OutputEvent::NewOutput { reg_name, name });
+    }
+
+    /// Records the make and model of the monitor of an output, which are part of the key of its
+    /// saved settings. Empty and unknown values are ignored.
+    pub fn set_output_identity(&mut self, reg_name: u32, make: &str, model: &str) {
+        let known = |value: &&str| !value.is_empty() && !value.eq_ignore_ascii_case("unknown");
+        let parts = [make, model].into_iter().filter(known).collect::<Vec<_>>();
+        let identity = (!parts.is_empty()).then(|| parts.join(" "));
+        let Some(output) = self.mut_output_by_reg_name(reg_name) else {
+            return;
+        };
+        if output.identity == identity {
+            return;
+        }
+        output.identity = identity;
+        if output.name.is_some() {
+            self.apply_saved_setting(reg_name);
+        }
+    }
+
+    fn apply_saved_setting(&mut self, reg_name: u32) {
+        let Some(output) = self.outputs.iter_mut().find(|o| o.reg_name == reg_name) else {
+            return;
+        };
+        if let Some(key) = output.settings_key() {
+            output.apply_setting(self.output_settings.get(&key));
+        }
+    }
+
+    pub fn remove_output(&mut self, reg_name: u32) -> Option<Output> {
+        let index = self.outputs.iter().position(|o| o.reg_name == reg_name)?;
+        let output = self.outputs.swap_remove(index);
+        if let Some(name) = &output.name {
+            self.events
+                .push_back(OutputEvent::RemoveOutput { name: name.clone() });
+        }
+        Some(output)
+    }
+
+    /// Called by the backend when it can, or can no longer, turn the output on or off
+    pub fn set_power_control(&mut self, reg_name: u32, supported: bool) {
+        if let Some(output) = self.mut_output_by_reg_name(reg_name) {
+            output.power_control = supported;
+        }
+    }
+
+    /// Returns the power mode requested for the output since the last call
+    pub fn take_power_request(&mut self, reg_name: u32) -> Option<bool> {
+        self.mut_output_by_reg_name(reg_name)?.power_request.take()
+    }
+
+    /// Called by the backend when an output is turned on or off
+    pub fn set_output_power(&mut self, reg_name: u32, power: bool) {
+        let Some(output) = self.mut_output_by_reg_name(reg_name) else {
+            return;
+        };
+        if output.power == power {
+            return;
+        }
+        eprintln!("Output {reg_name}: power = {power}");
+        output.power = power;
+        // Some compositors reset gamma while the output is off
+        if power {
+            output.color_changed = true;
+        }
+        if let Some(name) = output.name.clone() {
+            self.events
+                .push_back(OutputEvent::PowerChanged { name, power });
+        }
+    }
+
+    /// Replaces the output settings, e.g. after loading them, and applies them to the named
+    /// outputs
+    pub fn set_output_settings(&mut self, settings: OutputSettings) {
+        self.output_settings = settings;
+        for output in &mut self.outputs {
+            if output.name.is_some()
+                && let Some(key) = output.settings_key()
+            {
+                output.apply_setting(self.output_settings.get(&key));
+            }
+        }
+    }
+
+    /// Changes the settings of a named output with `f`, applies them and saves them, if the
+    /// output has a settings key. Returns `false` if there is no such output.
+    pub fn update_output_setting(
+        &mut self,
+        reg_name: u32,
+        f: impl FnOnce(&mut OutputSetting),
+    ) -> anyhow::Result<bool> {
+        let Some(output) = self
+            .outputs
+            .iter_mut()
+            .find(|output| output.reg_name == reg_name && output.name.is_some())
+        else {
+            return Ok(false);
+        };
+        let mut setting = output.setting();
+        f(&mut setting);
+        if let Some(key) = output.settings_key() {
+            self.output_settings.set(&key, setting)?;
+        }
+        output.apply_setting(setting);
+        Ok(true)
+    }
+
+    /// Makes an output follow the color of the output named `source`. The color is copied by
+    /// [`State::follow_links`].
+    pub fn link_output(
+        &mut self,
+        reg_name: u32,
+        source: &str,
+        offsets: ColorDeltas,
+    ) -> Result<(), String> {
+        let name = self
+            .output_by_reg_name(reg_name)
+            .and_then(|output| output.name.clone())
+            .ok_or("the output has no name")?;
+        if !self.outputs.iter().any(|o| o.name() == Some(source)) {
+            return Err(format!("no output named {source:?}"));
+        }
+        // Following the links from the source must not lead back to the output
+        let mut current = Some(source.to_owned());
+        while let Some(current_name) = current {
+            if current_name == name {
+                return Err(format!("{source:?} follows {name:?}"));
+            }
+            current = self
+                .outputs
+                .iter()
+                .find(|o| o.name() == Some(current_name.as_str()))
+                .and_then(|o| Some(o.link.as_ref()?.source.clone()));
+        }
+        if let Some(output) = self.mut_output_by_reg_name(reg_name) {
+            output.link = Some(Link {
+                source: source.to_owned(),
+                offsets,
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns `false` if the output was not linked
+    pub fn unlink_output(&mut self, reg_name: u32) -> bool {
+        self.mut_output_by_reg_name(reg_name)
+            .and_then(|output| output.link.take())
+            .is_some()
+    }
+
+    /// Sets the color of every linked output to the color of its source with the link's offsets
+    pub fn follow_links(&mut self) {
+        // Links have no cycles, so a chain of n links is followed in at most n passes
+        for _ in 0..self.outputs.len() {
+            let mut changed = false;
+            for i in 0..self.outputs.len() {
+                let Some(link) = &self.outputs[i].link else {
+                    continue;
+                };
+                let Some(source) = self
+                    .outputs
+                    .iter()
+                    .find(|o| o.name() == Some(link.source.as_str()))
+                else {
+                    continue;
+                };
+                let color = self.limits.clamp(source.color.with_deltas(link.offsets));
+                if color != self.outputs[i].color {
+                    self.outputs[i].set_color(color);
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    /// Returns the color that should be displayed on the output if it changed since the last call
+    ///
+    /// This is not necessarily [`Output::color`], since the displayed color may be adjusted
+    /// temporarily, e.g. while the user is idle.
+    pub fn take_color_change(&mut self, reg_name: u32) -> Option<Color> {
+        let output = self.output_by_reg_name(reg_name)?;
+        if !output.color_changed {
+            return None;
+        }
+        let color = self.displayed_color(output);
+        self.mut_output_by_reg_name(reg_name)?.color_changed = false;
+        Some(color)
+    }
+
+    fn displayed_color(&self, output: &Output) -> Color {
+        // Backends that cannot release an output restore its default ramp
+        if !output.managed || self.disabled || output.disabled {
+            return Color::default();
+        }
+        let mut color = if self.is_inhibited() {
+            Color::default()
+        } else {
+            let mut color = match self.active_app_rule {
+                Some(i) => self.app_rules[i].color.into(),
+                None => output.color,
+            };
+            if let Some(temporary) = output.temporary {
+                color = color.with_fields(temporary.fields);
+            }
+            self.limits
+                .clamp(color.with_calibration(output.calibration))
+        };
+        if self.idle {
+            color.brightness = color.brightness.min(self.idle_dim.brightness);
+        }
+        color
+    }
+
+    /// Forces all outputs to be redisplayed, e.g. after an adjustment has changed
+    fn redisplay_all(&mut self) {
+        for output in &mut self.outputs {
+            output.color_changed = true;
+        }
+    }
+
+    /// Returns `false` if the adjustments of all outputs are switched off
+    pub fn enabled(&self) -> bool {
+        !self.disabled
+    }
+
+    /// Switches the adjustments of all outputs off or back on. The colors are kept.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if self.disabled == enabled {
+            self.disabled = !enabled;
+            self.redisplay_all();
+        }
+    }
+
+    /// Sets whether the user is idle, which dims outputs if idle dimming is enabled
+    pub fn set_idle(&mut self, idle: bool) {
+        if self.idle != idle {
+            self.idle = idle;
+            self.redisplay_all();
+        }
+    }
+
+    pub fn set_idle_dim_brightness(&mut self, brightness: f64) {
+        if self.idle_dim.brightness != brightness {
+            self.idle_dim.brightness = brightness;
+            if self.idle {
+                self.redisplay_all();
+            }
+        }
+    }
+
+    /// Displays `fields` on top of the colors of the outputs of `target` until `duration` passes
+    pub fn set_temporary(&mut self, target: Target, fields: ColorFields, duration: Duration) {
+        let until = Instant::now() + duration;
+        let members = self.members(target);
+        for output in &mut self.outputs {
+            if members.contains(&output.reg_name) {
+                output.temporary = Some(Temporary { fields, until });
+                output.color_changed = true;
+            }
+        }
+    }
+
+    /// Reverts the temporary colors of the outputs of `target`. Returns `false` if there was
+    /// nothing to revert.
+    pub fn cancel_temporary(&mut self, target: Target) -> bool {
+        let mut cancelled = false;
+        let members = self.members(target);
+        for output in &mut self.outputs {
+            if members.contains(&output.reg_name) && output.temporary.take().is_some() {
+                output.color_changed = true;
+                cancelled = true;
+            }
+        }
+        cancelled
+    }
+
+    /// Reverts the temporary colors whose time is up. Returns `true` if there were any.
+    pub fn expire_temporaries(&mut self) -> bool {
+        let now = Instant::now();
+        let mut expired = false;
+        for output in &mut self.outputs {
+            if output.temporary.is_some_and(|t| t.until <= now) {
+                eprintln!("Output {}: temporary color expired", output.reg_name);
+                output.temporary = None;
+                output.color_changed = true;
+                expired = true;
+            }
+        }
+        expired
+    }
+
+    /// Returns when something has to be done next without any external event
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let temporaries = self
+            .outputs
+            .iter()
+            .filter_map(|output| Some(output.temporary?.until));
+        let inhibitors = self.inhibitors.iter().filter_map(|i| i.until);
+        temporaries.chain(inhibitors).min()
+    }
+
+    pub fn is_inhibited(&self) -> bool {
+        !self.inhibitors.is_empty()
+    }
+
+    pub fn inhibitors(&self) -> &[Inhibitor] {
+        &self.inhibitors
+    }
+
+    /// Displays neutral colors until the returned cookie is passed to [`State::uninhibit`]
+    pub fn inhibit(&mut self, owner: &str, reason: &str) -> u32 {
+        self.inhibit_until(owner, reason, None)
+    }
+
+    /// Like [`State::inhibit`], but the inhibitor is also released at `until`
+    pub fn inhibit_until(&mut self, owner: &str, reason: &str, until: Option<Instant>) -> u32 {
+        self.next_inhibit_cookie = self.next_inhibit_cookie.wrapping_add(1).max(1);
+        let cookie = self.next_inhibit_cookie;
+        eprintln!("Inhibited by {owner} ({reason:?}), cookie = {cookie}");
+        self.inhibitors.push(Inhibitor {
+            cookie,
+            owner: owner.to_owned(),
+            reason: reason.to_owned(),
+            until,
+        });
+        if self.inhibitors.len() == 1 {
+            self.redisplay_all();
+        }
+        cookie
+    }
+
+    /// Returns `false` if there is no inhibitor with this cookie
+    pub fn uninhibit(&mut self, cookie: u32) -> bool {
+        self.remove_inhibitors(|i| i.cookie == cookie)
+    }
+
+    /// Releases the inhibitors of a client that left the bus. Returns `true` if there were any.
+    pub fn uninhibit_owner(&mut self, owner: &str) -> bool {
+        self.remove_inhibitors(|i| i.owner == owner)
+    }
+
+    /// Releases the inhibitors whose time is up. Returns `true` if there were any.
+    pub fn expire_inhibitors(&mut self) -> bool {
+        let now = Instant::now();
+        self.remove_inhibitors(|i| i.until.is_some_and(|until| until <= now))
+    }
+
+    fn remove_inhibitors(&mut self, f: impl Fn(&Inhibitor) -> bool) -> bool {
+        let len = self.inhibitors.len();
+        self.inhibitors.retain(|i| {
+            if f(i) {
+                eprintln!("Inhibitor {} released", i.cookie);
+            }
+            !f(i)
+        });
+        if self.inhibitors.is_empty() && len != 0 {
+            self.redisplay_all();
+        }
+        self.inhibitors.len() != len
+    }
+
+    pub fn app_rules(&self) -> &[AppRule] {
+        &self.app_rules
+    }
+
+    /// Adds a rule, or replaces the rule with the same name
+    pub fn set_app_rule(&mut self, rule: AppRule) {
+        match self.app_rules.iter_mut().find(|r| r.name == rule.name) {
+            Some(old) => *old = rule,
+            None => self.app_rules.push(rule),
+        }
+        self.update_active_app_rule(true);
+    }
+
+    /// Returns `false` if there is no rule with this name
+    pub fn remove_app_rule(&mut self, name: &str) -> bool {
+        let len = self.app_rules.len();
+        self.app_rules.retain(|rule| rule.name != name);
+        let removed = self.app_rules.len() != len;
+        if removed {
+            self.update_active_app_rule(true);
+        }
+        removed
+    }
+
+    /// Called by the backend when windows change
+    pub fn set_toplevels(&mut self, toplevels: Vec<Toplevel>) {
+        self.toplevels = toplevels;
+        self.update_active_app_rule(false);
+    }
+
+    fn update_active_app_rule(&mut self, rules_changed: bool) {
+        let active = self
+            .app_rules
+            .iter()
+            .position(|rule| self.toplevels.iter().any(|toplevel| rule.matches(toplevel)));
+        // The active rule may have been edited without moving
+        if active != self.active_app_rule || (rules_changed && active.is_some()) {
+            if let Some(i) = active {
+                eprintln!("App rule {:?} is active", self.app_rules[i].name);
+            } else if self.active_app_rule.is_some() {
+                eprintln!("No app rule is active");
+            }
+            self.active_app_rule = active;
+            self.redisplay_all();
+        }
+    }
+
+    pub fn next_event(&mut self) -> Option<OutputEvent> {
+        self.events.pop_front()
+    }
+
+    pub fn output_by_reg_name(&self, reg_name: u32) -> Option<&Output> {
+        self.outputs
+            .iter()
+            .find(|output| output.reg_name() == reg_name)
+    }
+
+    pub fn mut_output_by_reg_name(&mut self, reg_name: u32) -> Option<&mut Output> {
+        self.outputs
+            .iter_mut()
+            .find(|output| output.reg_name() == reg_name)
+    }
+
+    /// Returns the aggregated color of all managed outputs, or the default color if there are
+    /// none
+    pub fn color(&self) -> Color {
+        self.target_color(Target::Root)
+    }
+
+    /// Returns `true` if all managed outputs have the same color
+    pub fn uniform(&self) -> bool {
+        let mut colors = self
+            .outputs
+            .iter()
+            .filter(|output| output.managed)
+            .map(|output| output.color());
+        let first = colors.next();
+        colors.all(|color| Some(color) == first)
+    }
+
+    pub fn groups(&self) -> &[Group] {
+        &self.groups
+    }
+
+    pub fn group(&self, id: u32) -> Option<&Group> {
+        self.groups.iter().find(|group| group.id == id)
+    }
+
+    /// Creates a group, which is exported once the event is handled. Fails if the group would
+    /// have the same object path as an existing one.
+    pub fn create_group(&mut self, name: &str, outputs: Vec<String>) -> Result<u32, String> {
+        if name.is_empty() {
+            return Err("the group name is empty".to_owned());
+        }
+        let path = format!("/groups/{}", object_name(name));
+        if self.groups.iter().any(|group| group.object_path() == path) {
+            return Err(format!("a group named {name:?} already exists"));
+        }
+        self.next_group_id += 1;
+        let id = self.next_group_id;
+        self.groups.push(Group {
+            id,
+            name: name.to_owned(),
+            outputs,
+        });
+        self.events.push_back(OutputEvent::NewGroup { id });
+        Ok(id)
+    }
+
+    /// Returns `false` if there is no group with this name
+    pub fn delete_group(&mut self, name: &str) -> bool {
+        let Some(index) = self.groups.iter().position(|group| group.name == name) else {
+            return false;
+        };
+        let group = self.groups.remove(index);
+        // A group whose event is still queued was never exported
+        let queued = self.events.len();
+        self.events
+            .retain(|event| !matches!(event, OutputEvent::NewGroup { id } if *id == group.id));
+        if self.events.len() == queued {
+            let path = group.object_path();
+            self.events.push_back(OutputEvent::RemoveGroup { path });
+        }
+        true
+    }
+
+    /// Returns `false` if the object of `target` was removed
+    pub fn target_exists(&self, target: Target) -> bool {
+        match target {
+            Target::Root => true,
+            Target::Output(reg_name) => self.output_by_reg_name(reg_name).is_some(),
+            Target::Group(id) => self.group(id).is_some(),
+        }
+    }
+
+    /// Returns the object path of `target`
+    pub fn target_path(&self, target: Target) -> Option<String> {
+        match target {
+            Target::Root => Some("/".to_owned()),
+            Target::Output(reg_name) => self.output_by_reg_name(reg_name)?.object_path(),
+            Target::Group(id) => Some(self.group(id)?.object_path()),
+        }
+    }
+
+    /// Returns the registry names of the outputs of `target`
+    pub fn members(&self, target: Target) -> Vec<u32> {
+        let group = match target {
+            Target::Group(id) => self.group(id),
+            _ => None,
+        };
+        self.outputs
+            .iter()
+            .filter(|output| match target {
+                Target::Root => output.managed,
+                Target::Output(reg_name) => output.reg_name == reg_name,
+                Target::Group(_) => {
+                    output.managed
+                        && group.is_some_and(|group| {
+                            output
+                                .name()
+                                .is_some_and(|name| group.outputs.iter().any(|n| n == name))
+                        })
+                }
+            })
+            .map(|output| output.reg_name)
+            .collect()
+    }
+
+    /// Returns the aggregated color of the outputs of `target`
+    pub fn target_color(&self, target: Target) -> Color {
+        let members = self.members(target);
+        aggregate_color(
+            self.outputs
+                .iter()
+                .filter(|output| members.contains(&output.reg_name)),
+            self.aggregation,
+            self.primary_output.as_deref(),
+        )
+    }
+
+    pub fn color_changed(&self) -> bool {
+        self.outputs.iter().any(|output| output.color_changed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_rules::RuleColor;
+
+    const REG_NAME: u32 = 1;
+
+    fn state_with_output(color: Color) -> State {
+        let mut state = State::default();
+        state.add_output(REG_NAME).set_color(color);
+        state.take_color_change(REG_NAME);
+        state
+    }
+
+    fn window(app_id: &str, activated: bool) -> Toplevel {
+        Toplevel {
+            app_id: app_id.to_owned(),
+            activated,
+            ..Toplevel::default()
+        }
+    }
+
+    #[test]
+    fn app_rule_is_reverted_when_focus_moves_away() {
+        let color = Color {
+            temp: 4000,
+            ..Color::default()
+        };
+        let mut state = state_with_output(color);
+        state.set_app_rule(AppRule {
+            name: "video".to_owned(),
+            app_id: Some("mpv".to_owned()),
+            title: None,
+            fullscreen: false,
+            color: RuleColor::default(),
+        });
+        assert_eq!(state.take_color_change(REG_NAME), None);
+
+        state.set_toplevels(vec![window("mpv", true), window("foot", false)]);
+        assert_eq!(state.take_color_change(REG_NAME), Some(Color::default()));
+
+        state.set_toplevels(vec![window("mpv", false), window("foot", true)]);
+        assert_eq!(state.take_color_change(REG_NAME), Some(color));
+        // The rule never changes the color of the output
+        assert_eq!(state.color(), color);
+    }
+
+    #[test]
+    fn idle_dimming_caps_brightness() {
+        let color = Color {
+            brightness: 0.8,
+            ..Color::default()
+        };
+        let mut state = state_with_output(color);
+        let displayed = |brightness| {
+            Some(Color {
+                brightness,
+                ..color
+            })
+        };
+
+        state.set_idle(true);
+        assert_eq!(state.take_color_change(REG_NAME), displayed(0.5));
+
+        // Updates made while displayed are kept, and shown below the cap
+        let output = state.mut_output_by_reg_name(REG_NAME).unwrap();
+        output.set_color(Color {
+            brightness: 0.3,
+            ..color
+        });
+        assert_eq!(state.take_color_change(REG_NAME), displayed(0.3));
+        let output = state.mut_output_by_reg_name(REG_NAME).unwrap();
+        output.set_color(Color {
+            brightness: 0.9,
+            ..color
+        });
+        assert_eq!(state.take_color_change(REG_NAME), displayed(0.5));
+
+        state.set_idle(false);
+        assert_eq!(state.take_color_change(REG_NAME), displayed(0.9));
+        assert_eq!(state.color().brightness, 0.9);
+    }
+
+    #[test]
+    fn deleting_a_new_group_drops_its_event() {
+        let mut state = state_with_output(Color::default());
+        state.create_group("new", vec![]).unwrap();
+        assert!(state.delete_group("new"));
+        assert!(state.next_event().is_none());
+
+        let id = state.create_group("old", vec![]).unwrap();
+        assert!(matches!(state.next_event(), Some(OutputEvent::NewGroup { id: new }) if new == id));
+        assert!(state.delete_group("old"));
+        assert!(matches!(
+            state.next_event(),
+            Some(OutputEvent::RemoveGroup { .. })
+        ));
+    }
+
+    #[test]
+    fn settings_keys() {
+        let mut state = state_with_output(Color::default());
+        state.set_synthesized_output_name(REG_NAME, "output-1".to_owned());
+        assert_eq!(state.outputs[0].settings_key(), None);
+        state.set_output_identity(REG_NAME, "Dell Inc.", "Unknown");
+        assert_eq!(
+            state.outputs[0].settings_key().as_deref(),
+            Some("Dell Inc.")
+        );
+
+        state.add_output(2);
+        state.set_output_name(2, "DP-1".to_owned());
+        assert_eq!(state.outputs[1].settings_key().as_deref(), Some("DP-1"));
+        state.set_output_identity(2, "Dell Inc.", "U2720Q");
+        assert_eq!(
+            state.outputs[1].settings_key().as_deref(),
+            Some("Dell Inc. U2720Q on DP-1")
+        );
+    }
+}
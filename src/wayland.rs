@@ -1,4 +1,3 @@
-use std::collections::VecDeque;
 use std::io::ErrorKind;
 use std::os::fd::{AsRawFd, RawFd};
 
@@ -7,24 +6,46 @@ use anyhow::{Result, bail};
 use wayrs_client::global::*;
 use wayrs_client::protocol::*;
 use wayrs_client::{Connection, EventCtx, IoMode};
+use wayrs_protocols::ext_idle_notify_v1::*;
+use wayrs_protocols::wlr_foreign_toplevel_management_unstable_v1::*;
 use wayrs_protocols::wlr_gamma_control_unstable_v1::*;
+use wayrs_protocols::wlr_output_power_management_unstable_v1::*;
+use wayrs_protocols::xdg_output_unstable_v1::*;
 
+use crate::app_rules::Toplevel;
+use crate::backend::Backend;
 use crate::color::{Color, colorramp_fill};
+use crate::state::State;
 
 pub struct Wayland {
     conn: Connection<WaylandState>,
-    pub state: WaylandState,
+    state: WaylandState,
 }
 
-pub struct WaylandState {
-    pub outputs: Vec<Output>,
-    pub gamma_manager: ZwlrGammaControlManagerV1,
-    pub events: VecDeque<WaylandEvent>,
+struct WaylandState {
+    shared: State,
+    outputs: Vec<Output>,
+    gamma_manager: ZwlrGammaControlManagerV1,
+    xdg_output_manager: Option<ZxdgOutputManagerV1>,
+    power_manager: Option<ZwlrOutputPowerManagerV1>,
+    idle: Option<Idle>,
+    toplevels: Vec<WlToplevel>,
 }
 
-pub enum WaylandEvent {
-    NewOutput { reg_name: u32, name: String },
-    RemoveOutput { name: String },
+/// A window, tracked for app rules
+struct WlToplevel {
+    handle: ZwlrForeignToplevelHandleV1,
+    /// State received since the last done event
+    pending: Toplevel,
+    current: Toplevel,
+}
+
+/// Idle notifications, used for idle dimming
+struct Idle {
+    notifier: ExtIdleNotifierV1,
+    seat: WlSeat,
+    /// The current notification and its timeout in seconds
+    notification: Option<(ExtIdleNotificationV1, u32)>,
 }
 
 impl AsRawFd for Wayland {
@@ -44,117 +65,220 @@ impl Wayland {
             );
         };
 
+        // Only used to get output names from compositors without wl_output v4
+        let xdg_output_manager = conn.bind_singleton(1..=3).ok();
+
+        let power_manager = conn.bind_singleton(1).ok();
+
+        let idle = match (conn.bind_singleton(1), conn.bind_singleton(1)) {
+            (Ok(notifier), Ok(seat)) => Some(Idle {
+                notifier,
+                seat,
+                notification: None,
+            }),
+            _ => None,
+        };
+
         let mut state = WaylandState {
+            shared: State::default(),
             outputs: Vec::new(),
             gamma_manager,
-            events: VecDeque::new(),
+            xdg_output_manager,
+            power_manager,
+            idle,
+            toplevels: Vec::new(),
         };
 
+        // Without it, app rules never match
+        let _ = conn.bind_singleton_with_cb(1..=3, toplevel_manager_cb);
+
         conn.add_registry_cb(wl_registry_cb);
         conn.dispatch_events(&mut state);
         conn.flush(IoMode::Blocking)?;
 
         Ok(Self { conn, state })
     }
+}
+
+impl Wayland {
+    /// Recreates the idle notification if the idle dimming timeout has changed
+    fn update_idle_notification(&mut self) {
+        let timeout = self.state.shared.idle_dim.timeout;
+        let Some(idle) = &mut self.state.idle else {
+            return;
+        };
+        if idle.notification.map_or(0, |(_, t)| t) == timeout {
+            return;
+        }
+
+        if let Some((notification, _)) = idle.notification.take() {
+            notification.destroy(&mut self.conn);
+        }
+        self.state.shared.set_idle(false);
+
+        if timeout != 0 {
+            let notification = idle.notifier.get_idle_notification_with_cb(
+                &mut self.conn,
+                timeout.saturating_mul(1000),
+                idle.seat,
+                idle_notification_cb,
+            );
+            idle.notification = Some((notification, timeout));
+        }
+    }
+}
+
+impl Backend for Wayland {
+    fn state(&self) -> &State {
+        &self.state.shared
+    }
+
+    fn state_mut(&mut self) -> &mut State {
+        &mut self.state.shared
+    }
 
-    pub fn poll(&mut self) -> Result<()> {
+    fn poll(&mut self) -> Result<()> {
         match self.conn.recv_events(IoMode::NonBlocking) {
             Ok(()) => self.conn.dispatch_events(&mut self.state),
             Err(e) if e.kind() == ErrorKind::WouldBlock => (),
             Err(e) => return Err(e.into()),
         }
 
+        self.update_idle_notification();
+
         for output in &mut self.state.outputs {
-            if output.color_changed {
-                output.update_displayed_color(&mut self.conn)?;
+            let managed = self
+                .state
+                .shared
+                .output_by_reg_name(output.reg_name)
+                .is_some_and(|o| o.managed());
+            if managed != output.gamma_control.is_some() {
+                output.set_managed(&mut self.conn, self.state.gamma_manager, managed);
+            }
+            if let Some(power) = self.state.shared.take_power_request(output.reg_name) {
+                if let Some(power_control) = output.power {
+                    let mode = if power {
+                        zwlr_output_power_v1::Mode::On
+                    } else {
+                        zwlr_output_power_v1::Mode::Off
+                    };
+                    power_control.set_mode(&mut self.conn, mode);
+                } else {
+                    eprintln!(
+                        "Output {}: power management is not supported",
+                        output.reg_name
+                    );
+                }
+            }
+            if output.ramp_size == 0 {
+                continue;
+            }
+            if let Some(color) = self.state.shared.take_color_change(output.reg_name) {
+                output.update_displayed_color(&mut self.conn, color)?;
             }
         }
 
         self.conn.flush(IoMode::Blocking)?;
         Ok(())
     }
-
-    pub fn next_event(&mut self) -> Option<WaylandEvent> {
-        self.state.events.pop_front()
-    }
 }
 
 #[derive(Debug)]
-pub struct Output {
+struct Output {
     reg_name: u32,
     wl: WlOutput,
-    name: Option<String>,
-    color: Color,
-    gamma_control: ZwlrGammaControlV1,
+    xdg: Option<ZxdgOutputV1>,
+    power: Option<ZwlrOutputPowerV1>,
+    /// `None` while the output is not managed
+    gamma_control: Option<ZwlrGammaControlV1>,
     ramp_size: usize,
-    color_changed: bool,
 }
 
 impl Output {
-    fn bind(
-        conn: &mut Connection<WaylandState>,
-        global: &Global,
-        gamma_manager: ZwlrGammaControlManagerV1,
-    ) -> Self {
+    fn bind(conn: &mut Connection<WaylandState>, global: &Global, state: &WaylandState) -> Self {
         eprintln!("New output: {}", global.name);
-        let output = global.bind_with_cb(conn, 4, wl_output_cb).unwrap();
+        let output = global.bind_with_cb(conn, 1..=4, wl_output_cb).unwrap();
+
+        // wl_output::name was added in v4, zxdg_output_v1::name in v2
+        let xdg = match state.xdg_output_manager {
+            Some(manager) if output.version() < 4 && manager.version() >= 2 => {
+                Some(manager.get_xdg_output_with_cb(conn, output, xdg_output_cb))
+            }
+            _ => None,
+        };
+
+        let power = state
+            .power_manager
+            .map(|manager| manager.get_output_power_with_cb(conn, output, output_power_cb));
+
         Self {
             reg_name: global.name,
             wl: output,
-            name: None,
-            color: Color::default(),
-            gamma_control: gamma_manager.get_gamma_control_with_cb(conn, output, gamma_control_cb),
+            xdg,
+            power,
+            gamma_control: Some(state.gamma_manager.get_gamma_control_with_cb(
+                conn,
+                output,
+                gamma_control_cb,
+            )),
             ramp_size: 0,
-            color_changed: true,
         }
     }
 
-    fn destroy(self, conn: &mut Connection<WaylandState>) {
-        eprintln!("Output {} removed", self.reg_name);
-        self.gamma_control.destroy(conn);
-        self.wl.release(conn);
-    }
-
-    pub fn reg_name(&self) -> u32 {
-        self.reg_name
-    }
-
-    pub fn color(&self) -> Color {
-        self.color
-    }
-
-    pub fn color_changed(&self) -> bool {
-        self.color_changed
+    /// Returns `true` if the name of this output will never be announced by the compositor
+    fn is_nameless(&self) -> bool {
+        self.wl.version() < 4 && self.xdg.is_none()
     }
 
-    pub fn set_color(&mut self, color: Color) {
-        if color != self.color {
-            self.color = color;
-            self.color_changed = true;
+    fn destroy(self, conn: &mut Connection<WaylandState>) {
+        eprintln!("Output {} removed", self.reg_name);
+        if let Some(xdg) = self.xdg {
+            xdg.destroy(conn);
+        }
+        if let Some(power) = self.power {
+            power.destroy(conn);
+        }
+        if let Some(gamma_control) = self.gamma_control {
+            gamma_control.destroy(conn);
+        }
+        if self.wl.version() >= 3 {
+            self.wl.release(conn);
         }
     }
 
-    pub fn object_path(&self) -> Option<String> {
-        self.name
-            .as_deref()
-            .map(|name| format!("/outputs/{}", name.replace('-', "_")))
-    }
-
-    fn update_displayed_color(&mut self, conn: &mut Connection<WaylandState>) -> Result<()> {
-        if self.ramp_size == 0 {
-            return Ok(());
+    /// Acquires or releases gamma control. The compositor restores the default ramp of outputs
+    /// whose gamma control is destroyed.
+    fn set_managed(
+        &mut self,
+        conn: &mut Connection<WaylandState>,
+        gamma_manager: ZwlrGammaControlManagerV1,
+        managed: bool,
+    ) {
+        if managed {
+            self.gamma_control =
+                Some(gamma_manager.get_gamma_control_with_cb(conn, self.wl, gamma_control_cb));
+        } else if let Some(gamma_control) = self.gamma_control.take() {
+            gamma_control.destroy(conn);
+            // Set again by the next gamma_size event
+            self.ramp_size = 0;
         }
+    }
 
+    fn update_displayed_color(
+        &self,
+        conn: &mut Connection<WaylandState>,
+        color: Color,
+    ) -> Result<()> {
         let file = shmemfdrs2::create_shmem(c"/ramp-buffer")?;
         file.set_len(self.ramp_size as u64 * 6)?;
         let mut mmap = unsafe { memmap2::MmapMut::map_mut(&file)? };
         let buf = bytemuck::cast_slice_mut::<u8, u16>(&mut mmap);
         let (r, rest) = buf.split_at_mut(self.ramp_size);
         let (g, b) = rest.split_at_mut(self.ramp_size);
-        colorramp_fill(r, g, b, self.ramp_size, self.color);
-        self.gamma_control.set_gamma(conn, file.into());
-
-        self.color_changed = false;
+        colorramp_fill(r, g, b, self.ramp_size, color);
+        if let Some(gamma_control) = self.gamma_control {
+            gamma_control.set_gamma(conn, file.into());
+        }
         Ok(())
     }
 }
@@ -166,19 +290,21 @@ fn wl_registry_cb(
 ) {
     match event {
         wl_registry::Event::Global(global) if global.is::<WlOutput>() => {
-            let mut output = Output::bind(conn, global, state.gamma_manager);
-            output.set_color(state.color());
-            output.update_displayed_color(conn).unwrap();
+            let output = Output::bind(conn, global, state);
+            state.shared.add_output(output.reg_name);
+            state
+                .shared
+                .set_power_control(output.reg_name, output.power.is_some());
+            // wl_output v1 has no done event, so there is nothing to wait for
+            if output.is_nameless() && output.wl.version() < 2 {
+                name_output(&mut state.shared, output.reg_name, None);
+            }
             state.outputs.push(output);
         }
         wl_registry::Event::GlobalRemove(name) => {
             if let Some(output_index) = state.outputs.iter().position(|o| o.reg_name == *name) {
                 let output = state.outputs.swap_remove(output_index);
-                if let Some(output_name) = &output.name {
-                    state.events.push_back(WaylandEvent::RemoveOutput {
-                        name: output_name.clone(),
-                    });
-                }
+                state.shared.remove_output(output.reg_name);
                 output.destroy(conn);
             }
         }
@@ -191,43 +317,174 @@ fn gamma_control_cb(ctx: EventCtx<WaylandState, ZwlrGammaControlV1>) {
         .state
         .outputs
         .iter()
-        .position(|o| o.gamma_control == ctx.proxy)
+        .position(|o| o.gamma_control == Some(ctx.proxy))
         .expect("Received event for unknown output");
     match ctx.event {
         zwlr_gamma_control_v1::Event::GammaSize(size) => {
             let output = &mut ctx.state.outputs[output_index];
             eprintln!("Output {}: ramp_size = {}", output.reg_name, size);
             output.ramp_size = size as usize;
-            output.update_displayed_color(ctx.conn).unwrap();
+            if let Some(color) = ctx.state.shared.take_color_change(output.reg_name) {
+                output.update_displayed_color(ctx.conn, color).unwrap();
+            }
         }
         zwlr_gamma_control_v1::Event::Failed => {
             let output = ctx.state.outputs.swap_remove(output_index);
             eprintln!("Output {}: gamma_control::Event::Failed", output.reg_name);
-            if let Some(output_name) = &output.name {
-                ctx.state.events.push_back(WaylandEvent::RemoveOutput {
-                    name: output_name.clone(),
-                });
-            }
+            ctx.state.shared.remove_output(output.reg_name);
             output.destroy(ctx.conn);
         }
         _ => (),
     }
 }
 
-fn wl_output_cb(ctx: EventCtx<WaylandState, WlOutput>) {
-    if let wl_output::Event::Name(name) = ctx.event {
-        let output = ctx
-            .state
-            .outputs
-            .iter_mut()
-            .find(|o| o.wl == ctx.proxy)
-            .unwrap();
-        let name = String::from_utf8(name.into_bytes()).expect("invalid output name");
-        eprintln!("Output {}: name = {name:?}", output.reg_name);
-        ctx.state.events.push_back(WaylandEvent::NewOutput {
-            reg_name: output.reg_name,
-            name: name.clone(),
+fn toplevel_manager_cb(ctx: EventCtx<WaylandState, ZwlrForeignToplevelManagerV1>) {
+    if let zwlr_foreign_toplevel_manager_v1::Event::Toplevel(handle) = ctx.event {
+        ctx.conn.set_callback_for(handle, toplevel_cb);
+        ctx.state.toplevels.push(WlToplevel {
+            handle,
+            pending: Toplevel::default(),
+            current: Toplevel::default(),
         });
-        output.name = Some(name);
+    }
+}
+
+fn toplevel_cb(ctx: EventCtx<WaylandState, ZwlrForeignToplevelHandleV1>) {
+    let Some(index) = ctx
+        .state
+        .toplevels
+        .iter()
+        .position(|t| t.handle == ctx.proxy)
+    else {
+        return;
+    };
+    let toplevel = &mut ctx.state.toplevels[index];
+    match ctx.event {
+        zwlr_foreign_toplevel_handle_v1::Event::AppId(app_id) => {
+            toplevel.pending.app_id = String::from_utf8_lossy(app_id.as_bytes()).into_owned();
+        }
+        zwlr_foreign_toplevel_handle_v1::Event::Title(title) => {
+            toplevel.pending.title = String::from_utf8_lossy(title.as_bytes()).into_owned();
+        }
+        zwlr_foreign_toplevel_handle_v1::Event::State(states) => {
+            let states = states
+                .chunks_exact(4)
+                .map(|s| u32::from_ne_bytes(s.try_into().unwrap()))
+                .collect::<Vec<_>>();
+            let has =
+                |state: zwlr_foreign_toplevel_handle_v1::State| states.contains(&u32::from(state));
+            toplevel.pending.activated = has(zwlr_foreign_toplevel_handle_v1::State::Activated);
+            toplevel.pending.fullscreen = has(zwlr_foreign_toplevel_handle_v1::State::Fullscreen);
+        }
+        zwlr_foreign_toplevel_handle_v1::Event::Done => {
+            if toplevel.current != toplevel.pending {
+                toplevel.current = toplevel.pending.clone();
+                update_toplevels(ctx.state);
+            }
+        }
+        zwlr_foreign_toplevel_handle_v1::Event::Closed => {
+            ctx.state.toplevels.swap_remove(index);
+            ctx.proxy.destroy(ctx.conn);
+            update_toplevels(ctx.state);
+        }
+        _ => (),
+    }
+}
+
+fn update_toplevels(state: &mut WaylandState) {
+    let toplevels = state.toplevels.iter().map(|t| t.current.clone()).collect();
+    state.shared.set_toplevels(toplevels);
+}
+
+fn output_power_cb(ctx: EventCtx<WaylandState, ZwlrOutputPowerV1>) {
+    let Some(output) = ctx
+        .state
+        .outputs
+        .iter_mut()
+        .find(|o| o.power == Some(ctx.proxy))
+    else {
+        return;
+    };
+    match ctx.event {
+        zwlr_output_power_v1::Event::Mode(mode) => {
+            let power = mode == zwlr_output_power_v1::Mode::On;
+            ctx.state.shared.set_output_power(output.reg_name, power);
+        }
+        zwlr_output_power_v1::Event::Failed => {
+            eprintln!("Output {}: output_power::Event::Failed", output.reg_name);
+            output.power = None;
+            ctx.state.shared.set_power_control(output.reg_name, false);
+            ctx.proxy.destroy(ctx.conn);
+        }
+        _ => (),
+    }
+}
+
+fn idle_notification_cb(ctx: EventCtx<WaylandState, ExtIdleNotificationV1>) {
+    match ctx.event {
+        ext_idle_notification_v1::Event::Idled => ctx.state.shared.set_idle(true),
+        ext_idle_notification_v1::Event::Resumed => ctx.state.shared.set_idle(false),
+        _ => (),
+    }
+}
+
+fn wl_output_cb(ctx: EventCtx<WaylandState, WlOutput>) {
+    let output = ctx
+        .state
+        .outputs
+        .iter()
+        .find(|o| o.wl == ctx.proxy)
+        .unwrap();
+    match ctx.event {
+        // Sent before the done event, so before nameless outputs are named
+        wl_output::Event::Geometry(geometry) => {
+            let make = String::from_utf8_lossy(geometry.make.as_bytes());
+            let model = String::from_utf8_lossy(geometry.model.as_bytes());
+            ctx.state
+                .shared
+                .set_output_identity(output.reg_name, &make, &model);
+        }
+        wl_output::Event::Name(name) => {
+            let name = String::from_utf8(name.into_bytes()).expect("invalid output name");
+            name_output(&mut ctx.state.shared, output.reg_name, Some(name));
+        }
+        wl_output::Event::Done if output.is_nameless() => {
+            name_output(&mut ctx.state.shared, output.reg_name, None);
+        }
+        _ => (),
+    }
+}
+
+fn xdg_output_cb(ctx: EventCtx<WaylandState, ZxdgOutputV1>) {
+    let output = ctx
+        .state
+        .outputs
+        .iter()
+        .find(|o| o.xdg == Some(ctx.proxy))
+        .unwrap();
+    match ctx.event {
+        zxdg_output_v1::Event::Name(name) => {
+            let name = String::from_utf8(name.into_bytes()).expect("invalid output name");
+            name_output(&mut ctx.state.shared, output.reg_name, Some(name));
+        }
+        zxdg_output_v1::Event::Done => {
+            name_output(&mut ctx.state.shared, output.reg_name, None);
+        }
+        _ => (),
+    }
+}
+
+/// Names an output once. Without a name from the compositor, `output-<reg_name>` is used, so that
+/// the output still gets a DBus object.
+fn name_output(state: &mut State, reg_name: u32, name: Option<String>) {
+    if state
+        .output_by_reg_name(reg_name)
+        .is_none_or(|output| output.name().is_some())
+    {
+        return;
+    }
+    match name {
+        Some(name) => state.set_output_name(reg_name, name),
+        None => state.set_synthesized_output_name(reg_name, format!("output-{reg_name}")),
     }
 }
@@ -0,0 +1,198 @@
+use std::os::fd::{AsRawFd, RawFd};
+
+use anyhow::{Context, Result};
+
+use x11rb::connection::Connection;
+use x11rb::protocol::Event;
+use x11rb::protocol::randr::{self, ConnectionExt as _};
+use x11rb::protocol::xproto::Window;
+use x11rb::rust_connection::RustConnection;
+
+use crate::backend::Backend;
+use crate::color::colorramp_fill;
+use crate::state::State;
+
+/// A backend using RandR CRTC gamma, for X sessions
+///
+/// Each active CRTC is exposed as an output named after its first connected output.
+pub struct X11 {
+    conn: RustConnection,
+    root: Window,
+    state: State,
+    crtcs: Vec<Crtc>,
+}
+
+#[derive(Debug)]
+struct Crtc {
+    id: randr::Crtc,
+    ramp_size: usize,
+    /// The red, green and blue ramps from before the CRTC was first seen, restored on exit
+    original: [Vec<u16>; 3],
+    /// Whether the original ramps were restored because the output is not managed
+    released: bool,
+}
+
+impl AsRawFd for X11 {
+    fn as_raw_fd(&self) -> RawFd {
+        self.conn.stream().as_raw_fd()
+    }
+}
+
+impl X11 {
+    pub fn new() -> Result<Self> {
+        let (conn, screen_num) = x11rb::connect(None).context("could not connect to X server")?;
+        let root = conn.setup().roots[screen_num].root;
+
+        conn.randr_query_version(1, 3)?
+            .reply()
+            .context("X server does not support RandR 1.3")?;
+        conn.randr_select_input(
+            root,
+            randr::NotifyMask::SCREEN_CHANGE
+                | randr::NotifyMask::CRTC_CHANGE
+                | randr::NotifyMask::OUTPUT_CHANGE,
+        )?;
+
+        let mut this = Self {
+            conn,
+            root,
+            state: State::default(),
+            crtcs: Vec::new(),
+        };
+        this.update_crtcs()?;
+        Ok(this)
+    }
+
+    /// Handles the pending events. Returns `true` if the CRTCs may have changed.
+    fn drain_events(&mut self) -> Result<bool> {
+        let mut resources_changed = false;
+        while let Some(event) = self.conn.poll_for_event()? {
+            if matches!(
+                event,
+                Event::RandrScreenChangeNotify(_) | Event::RandrNotify(_)
+            ) {
+                resources_changed = true;
+            }
+        }
+        Ok(resources_changed)
+    }
+
+    /// Synchronizes the outputs with the currently active CRTCs
+    fn update_crtcs(&mut self) -> Result<()> {
+        let resources = self
+            .conn
+            .randr_get_screen_resources_current(self.root)?
+            .reply()?;
+
+        let mut active = Vec::new();
+        for &crtc in &resources.crtcs {
+            let info = self
+                .conn
+                .randr_get_crtc_info(crtc, resources.config_timestamp)?
+                .reply()?;
+            let Some(&output) = info.outputs.first() else {
+                continue;
+            };
+            if info.mode == 0 {
+                continue;
+            }
+            let output = self
+                .conn
+                .randr_get_output_info(output, resources.config_timestamp)?
+                .reply()?;
+            active.push((crtc, String::from_utf8_lossy(&output.name).into_owned()));
+        }
+
+        self.crtcs.retain(|crtc| {
+            let keep = active.iter().any(|(id, _)| *id == crtc.id);
+            if !keep {
+                eprintln!("CRTC {} removed", crtc.id);
+                self.state.remove_output(crtc.id);
+            }
+            keep
+        });
+
+        for (id, name) in active {
+            if self.crtcs.iter().any(|crtc| crtc.id == id) {
+                continue;
+            }
+            let ramp_size = self.conn.randr_get_crtc_gamma_size(id)?.reply()?.size as usize;
+            eprintln!("New CRTC: {id}, ramp_size = {ramp_size}");
+            let gamma = self.conn.randr_get_crtc_gamma(id)?.reply()?;
+            // DPMS is not per CRTC, so the outputs have no power control
+            self.state.add_output(id);
+            self.state.set_output_name(id, name);
+            self.crtcs.push(Crtc {
+                id,
+                ramp_size,
+                original: [gamma.red, gamma.green, gamma.blue],
+                released: false,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Backend for X11 {
+    fn state(&self) -> &State {
+        &self.state
+    }
+
+    fn state_mut(&mut self) -> &mut State {
+        &mut self.state
+    }
+
+    fn poll(&mut self) -> Result<()> {
+        // Events that arrive while waiting for replies are buffered without making the fd
+        // readable, so they are drained again after the requests of `update_crtcs`
+        while self.drain_events()? {
+            self.update_crtcs()?;
+        }
+
+        for crtc in &mut self.crtcs {
+            if crtc.ramp_size == 0 {
+                continue;
+            }
+            let Some(color) = self.state.take_color_change(crtc.id) else {
+                continue;
+            };
+            // The original ramps may be a calibration, which unmanaged outputs keep
+            let managed = self
+                .state
+                .output_by_reg_name(crtc.id)
+                .is_some_and(|output| output.managed());
+            if !managed {
+                if !crtc.released {
+                    let [r, g, b] = &crtc.original;
+                    self.conn.randr_set_crtc_gamma(crtc.id, r, g, b)?;
+                    crtc.released = true;
+                }
+                continue;
+            }
+            crtc.released = false;
+
+            let mut ramp = vec![0; crtc.ramp_size * 3];
+            let (r, rest) = ramp.split_at_mut(crtc.ramp_size);
+            let (g, b) = rest.split_at_mut(crtc.ramp_size);
+            colorramp_fill(r, g, b, crtc.ramp_size, color);
+            self.conn.randr_set_crtc_gamma(crtc.id, r, g, b)?;
+        }
+
+        self.conn.flush()?;
+        Ok(())
+    }
+}
+
+/// Unlike Wayland compositors, the X server keeps the last ramps of a client that exits
+impl Drop for X11 {
+    fn drop(&mut self) {
+        for crtc in &self.crtcs {
+            let [r, g, b] = &crtc.original;
+            if let Err(e) = self.conn.randr_set_crtc_gamma(crtc.id, r, g, b) {
+                eprintln!("CRTC {}: could not restore gamma: {e}", crtc.id);
+            }
+        }
+        let _ = self.conn.flush();
+    }
+}
@@ -0,0 +1,363 @@
+//! Helpers for running the daemon against a private `dbus-daemon`
+
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use rustbus_service::rustbus::{
+    self, DuplexConn, MessageBuilder, MessageType, connection::Timeout,
+    connection::ll_conn::DBusAddr, message_builder::MarshalledMessage, params::Param,
+    params::Variant, wire::marshal::traits::Marshal, wire::unmarshal::traits::Variant as UnVariant,
+};
+
+pub const BUS_NAME: &str = "rs.wl-gammarelay";
+pub const IFACE: &str = "rs.wl.gammarelay";
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A temporary directory with a private bus and a daemon, using the headless backend unless
+/// started with [`TestEnv::x11`]
+pub struct TestEnv {
+    pub dir: PathBuf,
+    bus: Child,
+    daemon: Child,
+    /// Processes started by [`TestEnv::spawn`]
+    others: Vec<Child>,
+}
+
+impl TestEnv {
+    /// Starts the daemon with the given `--headless-output` specs
+    pub fn new(outputs: &[&str]) -> Self {
+        Self::with_args(outputs, &[])
+    }
+
+    /// Starts the daemon with the given `--headless-output` specs and extra `run` arguments
+    pub fn with_args(outputs: &[&str], args: &[&str]) -> Self {
+        Self::start(|dir| headless_command(dir, outputs, args))
+    }
+
+    /// Starts the daemon with the X11 backend, on the X server at `display`
+    pub fn x11(display: &str) -> Self {
+        Self::start(|dir| {
+            let mut daemon = daemon_command(dir);
+            daemon
+                .env("DISPLAY", display)
+                .env_remove("WAYLAND_DISPLAY")
+                .args(["run", "--backend", "x11"]);
+            daemon
+        })
+    }
+
+    fn start(daemon: impl FnOnce(&Path) -> Command) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "wl-gammarelay-test-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let socket = dir.join("bus");
+        let config = dir.join("bus.conf");
+        fs::write(
+            &config,
+            format!(
+                r#"<!DOCTYPE busconfig PUBLIC "-//freedesktop//DTD D-Bus Bus Configuration 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/busconfig.dtd">
+<busconfig>
+  <type>session</type>
+  <listen>unix:path={}</listen>
+  <policy context="default">
+    <allow send_destination="*" eavesdrop="true"/>
+    <allow eavesdrop="true"/>
+    <allow own="*"/>
+  </policy>
+</busconfig>
+"#,
+                socket.display()
+            ),
+        )
+        .unwrap();
+
+        let bus = Command::new("dbus-daemon")
+            .arg("--nofork")
+            .arg(format!("--config-file={}", config.display()))
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("dbus-daemon is required to run the tests");
+        wait_for(|| socket.exists());
+
+        let daemon = daemon(&dir).spawn().unwrap();
+
+        let this = Self {
+            dir,
+            bus,
+            daemon,
+            others: Vec::new(),
+        };
+        let mut conn = this.connect();
+        wait_for(|| this.try_get(&mut conn, "/", "Temperature").is_some());
+        this
+    }
+
+    pub fn connect(&self) -> DuplexConn {
+        let mut conn =
+            DuplexConn::connect_to_bus(DBusAddr::unix_path(self.dir.join("bus")), true).unwrap();
+        conn.send_hello(Timeout::Infinite).unwrap();
+        conn
+    }
+
+    /// Returns `true` if the daemon process is still running
+    pub fn daemon_alive(&mut self) -> bool {
+        self.daemon.try_wait().unwrap().is_none()
+    }
+
+    /// Sends SIGTERM to the daemon and waits until it exits
+    pub fn terminate_daemon(&mut self) -> ExitStatus {
+        unsafe { libc::kill(self.daemon.id() as libc::pid_t, libc::SIGTERM) };
+        wait_for(|| !self.daemon_alive());
+        self.daemon.wait().unwrap()
+    }
+
+    /// Starts another daemon on the same bus, which becomes the daemon of this environment.
+    /// Returns the previous daemon.
+    pub fn start_another(&mut self, outputs: &[&str], args: &[&str]) -> Child {
+        let daemon = spawn_daemon(&self.dir, outputs, args);
+        std::mem::replace(&mut self.daemon, daemon)
+    }
+
+    /// Starts another daemon on the same bus, which is killed with the environment
+    pub fn spawn_another(&mut self, outputs: &[&str], args: &[&str]) {
+        self.spawn(headless_command(&self.dir, outputs, args));
+    }
+
+    /// Returns a command running the daemon on the same bus, without arguments
+    pub fn command(&self) -> Command {
+        daemon_command(&self.dir)
+    }
+
+    /// Returns a command running a headless daemon on the same bus, like [`TestEnv::new`]
+    pub fn headless_command(&self, outputs: &[&str], args: &[&str]) -> Command {
+        headless_command(&self.dir, outputs, args)
+    }
+
+    /// Spawns `command`, which is killed with the environment
+    pub fn spawn(&mut self, mut command: Command) -> &mut Child {
+        self.others.push(command.spawn().unwrap());
+        self.others.last_mut().unwrap()
+    }
+
+    /// Returns the last ramp recorded for `output`, as `[r, g, b]`
+    pub fn last_ramp(&self, output: &str) -> Option<[Vec<u16>; 3]> {
+        let record = fs::read_to_string(self.dir.join("ramps")).ok()?;
+        let line = record
+            .lines()
+            .filter(|line| line.split(' ').next() == Some(output))
+            .last()?;
+        let mut channels = line.split(' ').skip(1).map(|channel| {
+            channel
+                .split(',')
+                .map(|v| v.parse().unwrap())
+                .collect::<Vec<u16>>()
+        });
+        Some([channels.next()?, channels.next()?, channels.next()?])
+    }
+
+    /// Waits until the last ramp recorded for `output` satisfies `f`
+    pub fn wait_for_ramp(&self, output: &str, f: impl Fn(&[Vec<u16>; 3]) -> bool) {
+        wait_for(|| self.last_ramp(output).is_some_and(|ramp| f(&ramp)));
+    }
+
+    fn try_get(&self, conn: &mut DuplexConn, path: &str, prop: &str) -> Option<MarshalledMessage> {
+        let reply = call(conn, get_msg(path, prop));
+        (reply.typ == MessageType::Reply).then_some(reply)
+    }
+
+    pub fn get<T>(&self, conn: &mut DuplexConn, path: &str, prop: &str) -> T
+    where
+        T: for<'a> rustbus::Unmarshal<'a, 'a>,
+    {
+        let reply = self
+            .try_get(conn, path, prop)
+            .unwrap_or_else(|| panic!("could not get {path} {prop}"));
+        reply
+            .body
+            .parser()
+            .get::<UnVariant>()
+            .unwrap()
+            .get::<T>()
+            .unwrap()
+    }
+
+    pub fn set(&self, conn: &mut DuplexConn, path: &str, prop: &str, value: impl Marshal) {
+        let reply = call(conn, set_msg(path, prop, value));
+        assert_eq!(reply.typ, MessageType::Reply, "could not set {path} {prop}");
+    }
+}
+
+fn spawn_daemon(dir: &Path, outputs: &[&str], args: &[&str]) -> Child {
+    headless_command(dir, outputs, args).spawn().unwrap()
+}
+
+/// The daemon, connected to the bus of the environment in `dir`
+fn daemon_command(dir: &Path) -> Command {
+    let mut daemon = Command::new(env!("CARGO_BIN_EXE_wl-gammarelay-rs"));
+    daemon
+        .env(
+            "DBUS_SESSION_BUS_ADDRESS",
+            format!("unix:path={}", dir.join("bus").display()),
+        )
+        // Keep the user's config and presets out of the tests
+        .env("XDG_CONFIG_HOME", dir.join("config"))
+        .env("XDG_DATA_HOME", dir.join("data"));
+    daemon
+}
+
+fn headless_command(dir: &Path, outputs: &[&str], args: &[&str]) -> Command {
+    let mut daemon = daemon_command(dir);
+    daemon
+        .args(["run", "--backend", "headless", "--headless-record"])
+        .arg(dir.join("ramps"));
+    for output in outputs {
+        daemon.args(["--headless-output", output]);
+    }
+    daemon.args(args);
+    daemon
+}
+
+impl Drop for TestEnv {
+    fn drop(&mut self) {
+        for daemon in std::iter::once(&mut self.daemon).chain(&mut self.others) {
+            let _ = daemon.kill();
+            let _ = daemon.wait();
+        }
+        let _ = self.bus.kill();
+        let _ = self.bus.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Builds an `a{sv}` argument
+pub fn dict<'a>(
+    entries: impl IntoIterator<Item = (&'a str, Param<'a, 'a>)>,
+) -> HashMap<&'a str, Variant<'a, 'a>> {
+    entries
+        .into_iter()
+        .map(|(key, value)| {
+            let variant = Variant {
+                sig: value.sig(),
+                value,
+            };
+            (key, variant)
+        })
+        .collect()
+}
+
+pub fn method_msg(path: &str, method: &str) -> MarshalledMessage {
+    MessageBuilder::new()
+        .call(method)
+        .on(path)
+        .with_interface(IFACE)
+        .at(BUS_NAME)
+        .build()
+}
+
+pub fn get_msg(path: &str, prop: &str) -> MarshalledMessage {
+    let mut msg = MessageBuilder::new()
+        .call("Get")
+        .on(path)
+        .with_interface("org.freedesktop.DBus.Properties")
+        .at(BUS_NAME)
+        .build();
+    msg.body.push_param(IFACE).unwrap();
+    msg.body.push_param(prop).unwrap();
+    msg
+}
+
+pub fn set_msg(path: &str, prop: &str, value: impl Marshal) -> MarshalledMessage {
+    set_iface_msg(path, IFACE, prop, value)
+}
+
+/// Like [`set_msg`], but for a property of another interface
+pub fn set_iface_msg(
+    path: &str,
+    iface: &str,
+    prop: &str,
+    value: impl Marshal,
+) -> MarshalledMessage {
+    let mut msg = MessageBuilder::new()
+        .call("Set")
+        .on(path)
+        .with_interface("org.freedesktop.DBus.Properties")
+        .at(BUS_NAME)
+        .build();
+    msg.body.push_param(iface).unwrap();
+    msg.body.push_param(prop).unwrap();
+    msg.body.push_variant(value).unwrap();
+    msg
+}
+
+/// Sends a method call and waits for its reply, which may be an error
+pub fn call(conn: &mut DuplexConn, msg: MarshalledMessage) -> MarshalledMessage {
+    let serial = conn.send.send_message_write_all(&msg).unwrap();
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let timeout = Timeout::Duration(deadline.saturating_duration_since(Instant::now()));
+        let reply = conn
+            .recv
+            .get_next_message(timeout)
+            .expect("no reply from the daemon");
+        if reply.dynheader.response_serial == Some(serial) {
+            return reply;
+        }
+    }
+}
+
+/// Subscribes to the signals matching `rule`
+pub fn add_match(conn: &mut DuplexConn, rule: &str) {
+    let reply = call(conn, rustbus::standard_messages::add_match(rule));
+    assert_eq!(reply.typ, MessageType::Reply);
+}
+
+/// Waits for the next signal with this member from the object at `path`, skipping other messages
+pub fn next_signal(conn: &mut DuplexConn, path: &str, member: &str) -> MarshalledMessage {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let timeout = Timeout::Duration(deadline.saturating_duration_since(Instant::now()));
+        let msg = conn
+            .recv
+            .get_next_message(timeout)
+            .expect("no signal from the daemon");
+        if msg.typ == MessageType::Signal
+            && msg.dynheader.object.as_deref() == Some(path)
+            && msg.dynheader.member.as_deref() == Some(member)
+        {
+            return msg;
+        }
+    }
+}
+
+/// Asserts that `reply` is an error with the given name
+pub fn assert_error(reply: &MarshalledMessage, name: &str) {
+    assert_eq!(reply.typ, MessageType::Error);
+    assert_eq!(reply.dynheader.error_name.as_deref(), Some(name));
+}
+
+pub fn wait_for(mut f: impl FnMut() -> bool) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !f() {
+        assert!(Instant::now() < deadline, "timed out");
+        sleep(Duration::from_millis(20));
+    }
+}
+
+pub fn object_path(output: &str) -> String {
+    format!("/outputs/{}", output.replace('-', "_"))
+}
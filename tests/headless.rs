@@ -0,0 +1,844 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn outputs_are_exported() {
+    let env = TestEnv::new(&["eDP-1:256", "HDMI-A-1:1024"]);
+    let mut conn = env.connect();
+
+    for output in ["eDP-1", "HDMI-A-1"] {
+        let path = object_path(output);
+        assert_eq!(env.get::<u16>(&mut conn, &path, "Temperature"), 6500);
+        assert_eq!(env.get::<f64>(&mut conn, &path, "Brightness"), 1.0);
+        assert!(!env.get::<bool>(&mut conn, &path, "Inverted"));
+    }
+
+    env.wait_for_ramp("eDP-1", |[r, _, _]| r.len() == 256);
+    env.wait_for_ramp("HDMI-A-1", |[r, _, _]| r.len() == 1024);
+}
+
+#[test]
+fn root_brightness_applies_to_all_outputs() {
+    let env = TestEnv::new(&["eDP-1", "HDMI-A-1"]);
+    let mut conn = env.connect();
+
+    env.set(&mut conn, "/", "Brightness", 0.5f64);
+    for output in ["eDP-1", "HDMI-A-1"] {
+        assert_eq!(
+            env.get::<f64>(&mut conn, &object_path(output), "Brightness"),
+            0.5
+        );
+        env.wait_for_ramp(output, |ramp| {
+            ramp.iter()
+                .all(|channel| *channel.last().unwrap() == u16::MAX / 2)
+        });
+    }
+}
+
+#[test]
+fn output_temperature_is_independent() {
+    let env = TestEnv::new(&["eDP-1", "HDMI-A-1"]);
+    let mut conn = env.connect();
+
+    env.set(&mut conn, &object_path("eDP-1"), "Temperature", 3000u16);
+    assert_eq!(
+        env.get::<u16>(&mut conn, &object_path("eDP-1"), "Temperature"),
+        3000
+    );
+    assert_eq!(
+        env.get::<u16>(&mut conn, &object_path("HDMI-A-1"), "Temperature"),
+        6500
+    );
+    assert_eq!(env.get::<u16>(&mut conn, "/", "Temperature"), 4750);
+
+    // Warm colors reduce blue the most
+    env.wait_for_ramp("eDP-1", |[r, _, b]| b.last() < r.last());
+    env.wait_for_ramp("HDMI-A-1", |[r, _, b]| b.last() == r.last());
+}
+
+#[test]
+fn toggle_inverted() {
+    let env = TestEnv::new(&["eDP-1"]);
+    let mut conn = env.connect();
+
+    call(&mut conn, method_msg("/", "ToggleInverted"));
+    assert!(env.get::<bool>(&mut conn, "/", "Inverted"));
+    env.wait_for_ramp("eDP-1", |[r, _, _]| {
+        r[0] == u16::MAX && *r.last().unwrap() == 0
+    });
+}
+
+#[test]
+fn power() {
+    let env = TestEnv::new(&["eDP-1"]);
+    let mut conn = env.connect();
+    let path = object_path("eDP-1");
+
+    assert!(env.get::<bool>(&mut conn, &path, "Power"));
+    env.set(&mut conn, &path, "Power", false);
+    wait_for(|| !env.get::<bool>(&mut conn, &path, "Power"));
+    env.set(&mut conn, &path, "Power", true);
+    wait_for(|| env.get::<bool>(&mut conn, &path, "Power"));
+}
+
+#[test]
+fn set_color() {
+    let env = TestEnv::new(&["eDP-1"]);
+    let mut conn = env.connect();
+
+    let mut msg = method_msg("/", "SetColor");
+    let color = dict([
+        ("Temperature", 4000u16.into()),
+        ("Brightness", 0.5f64.into()),
+    ]);
+    msg.body.push_param(color).unwrap();
+    call(&mut conn, msg);
+
+    let path = object_path("eDP-1");
+    assert_eq!(env.get::<u16>(&mut conn, &path, "Temperature"), 4000);
+    assert_eq!(env.get::<f64>(&mut conn, &path, "Brightness"), 0.5);
+}
+
+#[test]
+fn managed_objects() {
+    use std::collections::HashMap;
+
+    use rustbus_service::rustbus::{
+        MessageBuilder, MessageType, wire::ObjectPath, wire::unmarshal::traits::Variant,
+    };
+
+    let env = TestEnv::new(&["eDP-1", "HDMI-A-1"]);
+    let mut conn = env.connect();
+    let mut msg = method_msg("/", "CreateGroup");
+    msg.body.push_param("external").unwrap();
+    msg.body.push_param(&["HDMI-A-1"][..]).unwrap();
+    call(&mut conn, msg);
+    wait_for(|| {
+        call(&mut conn, get_msg("/groups/external", "Temperature")).typ == MessageType::Reply
+    });
+
+    let msg = MessageBuilder::new()
+        .call("GetManagedObjects")
+        .on("/")
+        .with_interface("org.freedesktop.DBus.ObjectManager")
+        .at(BUS_NAME)
+        .build();
+    let reply = call(&mut conn, msg);
+    let objects = reply
+        .body
+        .parser()
+        .get::<HashMap<ObjectPath<&str>, HashMap<&str, HashMap<&str, Variant>>>>()
+        .unwrap();
+
+    let mut paths = objects
+        .keys()
+        .map(|path| path.as_ref())
+        .collect::<Vec<&str>>();
+    paths.sort();
+    assert_eq!(
+        paths,
+        [
+            "/groups/external",
+            "/org/gnome/SettingsDaemon/Color",
+            "/org/kde/KWin/NightLight",
+            "/outputs/HDMI_A_1",
+            "/outputs/eDP_1",
+        ]
+    );
+    assert_eq!(
+        objects[&ObjectPath::new("/outputs/eDP_1").unwrap()][IFACE]["Temperature"]
+            .get::<u16>()
+            .unwrap(),
+        6500
+    );
+
+    // Every object reports the same properties as `GetAll`
+    for (path, interfaces) in &objects {
+        for (iface, props) in interfaces {
+            let mut msg = MessageBuilder::new()
+                .call("GetAll")
+                .on(path.as_ref())
+                .with_interface("org.freedesktop.DBus.Properties")
+                .at(BUS_NAME)
+                .build();
+            msg.body.push_param(*iface).unwrap();
+            let reply = call(&mut conn, msg);
+            let all = reply.body.parser().get::<HashMap<&str, Variant>>().unwrap();
+            let mut names = props.keys().collect::<Vec<_>>();
+            let mut all_names = all.keys().collect::<Vec<_>>();
+            names.sort();
+            all_names.sort();
+            assert_eq!(
+                names,
+                all_names,
+                "properties of {iface} on {}",
+                path.as_ref()
+            );
+        }
+    }
+}
+
+#[test]
+fn object_signals() {
+    use std::collections::HashMap;
+
+    use rustbus_service::rustbus::{
+        DuplexConn, wire::ObjectPath, wire::unmarshal::traits::Variant,
+    };
+
+    fn root_temperature(signals: &mut DuplexConn) -> u16 {
+        loop {
+            let sig = next_signal(signals, "/", "PropertiesChanged");
+            let mut parser = sig.body.parser();
+            assert_eq!(parser.get::<&str>().unwrap(), IFACE);
+            let changed = parser.get::<HashMap<&str, Variant>>().unwrap();
+            if let Some(temperature) = changed.get("Temperature") {
+                return temperature.get().unwrap();
+            }
+        }
+    }
+
+    let env = TestEnv::new(&["eDP-1", "HDMI-A-1"]);
+    let mut conn = env.connect();
+    // `call` skips the signals sent before the reply, so they are received on their own
+    // connection
+    let mut signals = env.connect();
+    add_match(&mut signals, &format!("type='signal',sender='{BUS_NAME}'"));
+
+    let mut msg = method_msg("/", "CreateGroup");
+    msg.body.push_param("external").unwrap();
+    msg.body.push_param(&["HDMI-A-1"][..]).unwrap();
+    call(&mut conn, msg);
+    let sig = next_signal(&mut signals, "/", "InterfacesAdded");
+    let mut parser = sig.body.parser();
+    let path = parser.get::<ObjectPath<&str>>().unwrap();
+    assert_eq!(path.as_ref(), "/groups/external");
+    let interfaces = parser
+        .get::<HashMap<&str, HashMap<&str, Variant>>>()
+        .unwrap();
+    assert_eq!(interfaces[IFACE]["Temperature"].get::<u16>().unwrap(), 6500);
+
+    let mut msg = method_msg("/", "DeleteGroup");
+    msg.body.push_param("external").unwrap();
+    call(&mut conn, msg);
+    let sig = next_signal(&mut signals, "/", "InterfacesRemoved");
+    let mut parser = sig.body.parser();
+    let path = parser.get::<ObjectPath<&str>>().unwrap();
+    assert_eq!(path.as_ref(), "/groups/external");
+    assert_eq!(parser.get::<Vec<&str>>().unwrap(), [IFACE]);
+
+    // The headless backend has no hotplug, so the output leaves the root by becoming unmanaged
+    let path = object_path("HDMI-A-1");
+    env.set(&mut conn, &path, "Temperature", 4000u16);
+    assert_eq!(root_temperature(&mut signals), 5250);
+    env.set(&mut conn, &path, "Managed", false);
+    assert_eq!(root_temperature(&mut signals), 6500);
+}
+
+#[test]
+fn presets() {
+    let env = TestEnv::new(&["eDP-1"]);
+    let mut conn = env.connect();
+    let preset_msg = |method, name: &str| {
+        let mut msg = method_msg("/", method);
+        msg.body.push_param(name).unwrap();
+        msg
+    };
+
+    env.set(&mut conn, "/", "Temperature", 4000u16);
+    call(&mut conn, preset_msg("SavePreset", "night"));
+    assert_eq!(env.get::<String>(&mut conn, "/", "ActivePreset"), "night");
+
+    env.set(&mut conn, "/", "Temperature", 6500u16);
+    assert_eq!(env.get::<String>(&mut conn, "/", "ActivePreset"), "");
+
+    call(&mut conn, preset_msg("ApplyPreset", "night"));
+    assert_eq!(env.get::<u16>(&mut conn, "/", "Temperature"), 4000);
+    assert!(env.dir.join("data/wl-gammarelay-rs/presets.toml").exists());
+}
+
+#[test]
+fn inhibit() {
+    let env = TestEnv::new(&["eDP-1"]);
+    let mut conn = env.connect();
+    env.set(&mut conn, "/", "Brightness", 0.5f64);
+    env.wait_for_ramp("eDP-1", |[r, _, _]| *r.last().unwrap() == u16::MAX / 2);
+
+    // Inhibit from a separate connection, which is then closed
+    {
+        let mut inhibitor = env.connect();
+        let mut msg = method_msg("/", "Inhibit");
+        msg.body.push_param("screenshot").unwrap();
+        call(&mut inhibitor, msg);
+        assert!(env.get::<bool>(&mut conn, "/", "Inhibited"));
+        assert_eq!(env.get::<f64>(&mut conn, "/", "Brightness"), 0.5);
+        env.wait_for_ramp("eDP-1", |[r, _, _]| *r.last().unwrap() == u16::MAX);
+    }
+
+    wait_for(|| !env.get::<bool>(&mut conn, "/", "Inhibited"));
+    env.wait_for_ramp("eDP-1", |[r, _, _]| *r.last().unwrap() == u16::MAX / 2);
+}
+
+#[test]
+fn temporary() {
+    let env = TestEnv::new(&["eDP-1"]);
+    let mut conn = env.connect();
+
+    let mut msg = method_msg("/", "SetTemporary");
+    msg.body
+        .push_param(dict([("Brightness", 0.5f64.into())]))
+        .unwrap();
+    msg.body.push_param(600u32).unwrap();
+    call(&mut conn, msg);
+
+    let remaining = env.get::<u32>(&mut conn, "/", "TemporaryRemaining");
+    assert!((599..=600).contains(&remaining));
+    assert_eq!(env.get::<f64>(&mut conn, "/", "Brightness"), 1.0);
+    env.wait_for_ramp("eDP-1", |[r, _, _]| *r.last().unwrap() == u16::MAX / 2);
+
+    call(&mut conn, method_msg("/", "CancelTemporary"));
+    env.wait_for_ramp("eDP-1", |[r, _, _]| *r.last().unwrap() == u16::MAX);
+    assert_eq!(env.get::<u32>(&mut conn, "/", "TemporaryRemaining"), 0);
+}
+
+#[test]
+fn temporary_expires() {
+    let env = TestEnv::new(&["eDP-1"]);
+    let mut conn = env.connect();
+
+    let mut msg = method_msg("/", "SetTemporary");
+    msg.body
+        .push_param(dict([("Brightness", 0.5f64.into())]))
+        .unwrap();
+    msg.body.push_param(1u32).unwrap();
+    call(&mut conn, msg);
+
+    // The test timeout is far longer than the override
+    wait_for(|| env.get::<u32>(&mut conn, "/", "TemporaryRemaining") == 0);
+    env.wait_for_ramp("eDP-1", |[r, _, _]| *r.last().unwrap() == u16::MAX);
+    assert_eq!(env.get::<f64>(&mut conn, "/", "Brightness"), 1.0);
+}
+
+#[test]
+fn groups() {
+    use rustbus_service::rustbus::MessageType;
+
+    let env = TestEnv::new(&["eDP-1", "HDMI-A-1", "DP-1"]);
+    let mut conn = env.connect();
+
+    let mut msg = method_msg("/", "CreateGroup");
+    msg.body.push_param("external").unwrap();
+    msg.body.push_param(&["HDMI-A-1", "DP-1"][..]).unwrap();
+    call(&mut conn, msg);
+    let mut msg = method_msg("/", "CreateGroup");
+    msg.body.push_param("external").unwrap();
+    msg.body.push_param(&["eDP-1"][..]).unwrap();
+    assert_error(
+        &call(&mut conn, msg),
+        "org.freedesktop.DBus.Error.InvalidArgs",
+    );
+
+    let path = "/groups/external";
+    wait_for(|| call(&mut conn, get_msg(path, "Temperature")).typ == MessageType::Reply);
+    env.set(&mut conn, path, "Temperature", 4000u16);
+    assert_eq!(env.get::<u16>(&mut conn, path, "Temperature"), 4000);
+    for output in ["HDMI-A-1", "DP-1"] {
+        assert_eq!(
+            env.get::<u16>(&mut conn, &object_path(output), "Temperature"),
+            4000
+        );
+    }
+    assert_eq!(
+        env.get::<u16>(&mut conn, &object_path("eDP-1"), "Temperature"),
+        6500
+    );
+
+    let mut msg = method_msg("/", "DeleteGroup");
+    msg.body.push_param("external").unwrap();
+    call(&mut conn, msg);
+    wait_for(|| call(&mut conn, get_msg(path, "Temperature")).typ != MessageType::Reply);
+}
+
+#[test]
+fn managed() {
+    let env = TestEnv::new(&["eDP-1", "DP-1"]);
+    let mut conn = env.connect();
+    let path = object_path("DP-1");
+
+    env.set(&mut conn, "/", "Brightness", 0.5f64);
+    env.wait_for_ramp("DP-1", |[r, _, _]| *r.last().unwrap() == u16::MAX / 2);
+    env.set(&mut conn, &path, "Managed", false);
+    assert!(!env.get::<bool>(&mut conn, &path, "Managed"));
+    env.wait_for_ramp("DP-1", |[r, _, _]| *r.last().unwrap() == u16::MAX);
+
+    env.set(&mut conn, "/", "Temperature", 4000u16);
+    assert_eq!(env.get::<u16>(&mut conn, &path, "Temperature"), 6500);
+    assert_eq!(env.get::<u16>(&mut conn, "/", "Temperature"), 4000);
+
+    let settings = env.dir.join("data/wl-gammarelay-rs/outputs.toml");
+    assert!(std::fs::read_to_string(settings).unwrap().contains("DP-1"));
+
+    env.set(&mut conn, &path, "Managed", true);
+    env.wait_for_ramp("DP-1", |[r, _, _]| *r.last().unwrap() == u16::MAX / 2);
+}
+
+#[test]
+fn invalid_output_settings() {
+    let mut env = TestEnv::new(&["eDP-1"]);
+    let dir = env.dir.join("data/wl-gammarelay-rs");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("outputs.toml"),
+        "[output.eDP-1]\ngamma_scale = nan\n",
+    )
+    .unwrap();
+
+    let mut valid = env.start_another(&["eDP-1"], &["--bus-name", "rs.wl-gammarelay.invalid"]);
+    wait_for(|| !env.daemon_alive());
+    valid.kill().unwrap();
+    valid.wait().unwrap();
+}
+
+#[test]
+fn enabled() {
+    let env = TestEnv::new(&["eDP-1", "DP-1"]);
+    let mut conn = env.connect();
+
+    env.set(&mut conn, "/", "Brightness", 0.5f64);
+    env.wait_for_ramp("eDP-1", |[r, _, _]| *r.last().unwrap() == u16::MAX / 2);
+
+    call(&mut conn, method_msg("/", "ToggleEnabled"));
+    assert!(!env.get::<bool>(&mut conn, "/", "Enabled"));
+    assert_eq!(env.get::<f64>(&mut conn, "/", "Brightness"), 0.5);
+    for output in ["eDP-1", "DP-1"] {
+        env.wait_for_ramp(output, |[r, _, _]| *r.last().unwrap() == u16::MAX);
+    }
+
+    env.set(&mut conn, "/", "Enabled", true);
+    env.set(&mut conn, &object_path("DP-1"), "Enabled", false);
+    env.wait_for_ramp("eDP-1", |[r, _, _]| *r.last().unwrap() == u16::MAX / 2);
+    env.wait_for_ramp("DP-1", |[r, _, _]| *r.last().unwrap() == u16::MAX);
+    assert_eq!(
+        env.get::<f64>(&mut conn, &object_path("DP-1"), "Brightness"),
+        0.5
+    );
+}
+
+#[test]
+fn calibration() {
+    let env = TestEnv::new(&["eDP-1", "DP-1"]);
+    let mut conn = env.connect();
+    let path = object_path("DP-1");
+
+    env.set(&mut conn, &path, "BrightnessScale", 0.5f64);
+    env.set(&mut conn, &path, "TemperatureOffset", -300i16);
+    env.wait_for_ramp("DP-1", |[r, _, _]| *r.last().unwrap() == u16::MAX / 2);
+
+    env.set(&mut conn, "/", "Temperature", 4000u16);
+    assert_eq!(env.get::<u16>(&mut conn, &path, "Temperature"), 4000);
+    assert_eq!(env.get::<i16>(&mut conn, &path, "TemperatureOffset"), -300);
+    env.wait_for_ramp("eDP-1", |[_, _, b]| *b.last().unwrap() < u16::MAX);
+    // The offset keeps DP-1 warmer, which reduces blue relative to red
+    let blue_ratio =
+        |[r, _, b]: &[Vec<u16>; 3]| *b.last().unwrap() as f64 / *r.last().unwrap() as f64;
+    let edp_ratio = blue_ratio(&env.last_ramp("eDP-1").unwrap());
+    env.wait_for_ramp("DP-1", |ramp| {
+        *ramp[0].last().unwrap() == u16::MAX / 2 && blue_ratio(ramp) < edp_ratio
+    });
+
+    let reply = call(&mut conn, set_msg(&path, "GammaScale", 0.0f64));
+    assert_error(&reply, "org.freedesktop.DBus.Error.InvalidArgs");
+}
+
+#[test]
+fn root_aggregation() {
+    let env = TestEnv::new(&["eDP-1", "DP-1"]);
+    let mut conn = env.connect();
+
+    assert!(env.get::<bool>(&mut conn, "/", "Uniform"));
+    env.set(&mut conn, &object_path("eDP-1"), "Temperature", 3000u16);
+    assert!(!env.get::<bool>(&mut conn, "/", "Uniform"));
+    assert_eq!(env.get::<u16>(&mut conn, "/", "Temperature"), 4750);
+
+    for (aggregation, temp) in [("min", 3000), ("max", 6500), ("primary", 3000)] {
+        env.set(&mut conn, "/", "RootAggregation", aggregation);
+        assert_eq!(env.get::<u16>(&mut conn, "/", "Temperature"), temp);
+    }
+    let reply = call(&mut conn, set_msg("/", "RootAggregation", "median"));
+    assert_error(&reply, "org.freedesktop.DBus.Error.InvalidArgs");
+}
+
+#[test]
+fn mean_of_many_outputs() {
+    let outputs = (1..=8).map(|i| format!("DP-{i}")).collect::<Vec<_>>();
+    let outputs = outputs.iter().map(String::as_str).collect::<Vec<_>>();
+    let env = TestEnv::new(&outputs);
+    let mut conn = env.connect();
+
+    env.set(&mut conn, "/", "Temperature", 10000u16);
+    assert_eq!(env.get::<u16>(&mut conn, "/", "Temperature"), 10000);
+}
+
+#[test]
+fn links() {
+    let env = TestEnv::new(&["eDP-1", "HDMI-A-1"]);
+    let mut conn = env.connect();
+    let path = object_path("HDMI-A-1");
+
+    let mut msg = method_msg(&path, "LinkToWithOffsets");
+    msg.body.push_param("eDP-1").unwrap();
+    msg.body
+        .push_param(dict([("Temperature", (-300i16).into())]))
+        .unwrap();
+    call(&mut conn, msg);
+    assert_eq!(env.get::<String>(&mut conn, &path, "LinkedTo"), "eDP-1");
+    assert_eq!(env.get::<u16>(&mut conn, &path, "Temperature"), 6200);
+
+    env.set(&mut conn, &object_path("eDP-1"), "Temperature", 4000u16);
+    assert_eq!(env.get::<u16>(&mut conn, &path, "Temperature"), 3700);
+
+    // A linked output cannot be changed directly
+    assert_error(
+        &call(&mut conn, set_msg(&path, "Temperature", 5000u16)),
+        "org.freedesktop.DBus.Error.InvalidArgs",
+    );
+    assert_error(
+        &call(&mut conn, method_msg(&path, "ToggleInverted")),
+        "org.freedesktop.DBus.Error.InvalidArgs",
+    );
+    assert_eq!(env.get::<u16>(&mut conn, &path, "Temperature"), 3700);
+
+    // Links must not form a cycle
+    let mut msg = method_msg(&object_path("eDP-1"), "LinkTo");
+    msg.body.push_param("HDMI-A-1").unwrap();
+    assert_error(
+        &call(&mut conn, msg),
+        "org.freedesktop.DBus.Error.InvalidArgs",
+    );
+
+    call(&mut conn, method_msg(&path, "Unlink"));
+    assert_eq!(env.get::<String>(&mut conn, &path, "LinkedTo"), "");
+    env.set(&mut conn, &object_path("eDP-1"), "Temperature", 5000u16);
+    assert_eq!(env.get::<u16>(&mut conn, &path, "Temperature"), 3700);
+}
+
+#[test]
+fn history() {
+    use std::collections::HashMap;
+
+    use rustbus_service::rustbus::wire::unmarshal::traits::Variant;
+
+    let env = TestEnv::new(&["eDP-1"]);
+    let mut conn = env.connect();
+    env.set(&mut conn, "/", "Temperature", 4000u16);
+    env.set(&mut conn, "/", "Temperature", 3000u16);
+
+    let undone = call(&mut conn, method_msg("/", "Undo"));
+    assert!(undone.body.parser().get::<bool>().unwrap());
+    assert_eq!(env.get::<u16>(&mut conn, "/", "Temperature"), 4000);
+
+    let redone = call(&mut conn, method_msg("/", "Redo"));
+    assert!(redone.body.parser().get::<bool>().unwrap());
+    assert_eq!(env.get::<u16>(&mut conn, "/", "Temperature"), 3000);
+
+    let redone = call(&mut conn, method_msg("/", "Redo"));
+    assert!(!redone.body.parser().get::<bool>().unwrap());
+
+    let history = call(&mut conn, method_msg("/", "GetHistory"));
+    let history = history
+        .body
+        .parser()
+        .get::<Vec<(u64, String, HashMap<&str, Variant>)>>()
+        .unwrap();
+    assert_eq!(history.len(), 2);
+    let (_, sender, changes) = &history[1];
+    assert!(sender.starts_with(':'));
+    assert_eq!(changes["Temperature"].get::<u16>().unwrap(), 3000);
+    assert!(!changes["Undone"].get::<bool>().unwrap());
+}
+
+#[test]
+fn limits() {
+    let env = TestEnv::with_args(
+        &["eDP-1"],
+        &["--min-brightness", "0.2", "--max-temperature", "5000"],
+    );
+    let mut conn = env.connect();
+    assert_eq!(env.get::<f64>(&mut conn, "/", "MinBrightness"), 0.2);
+    assert_eq!(env.get::<u16>(&mut conn, "/", "MaxTemperature"), 5000);
+    // The outputs exist before the limits are installed
+    assert_eq!(env.get::<u16>(&mut conn, "/", "Temperature"), 5000);
+    assert_eq!(
+        env.get::<u16>(&mut conn, "/outputs/eDP_1", "Temperature"),
+        5000
+    );
+
+    env.set(&mut conn, "/", "Brightness", 0.0f64);
+    assert_eq!(env.get::<f64>(&mut conn, "/", "Brightness"), 0.2);
+
+    let mut msg = method_msg("/", "UpdateTemperature");
+    msg.body.push_param(1000i16).unwrap();
+    call(&mut conn, msg);
+    assert_eq!(env.get::<u16>(&mut conn, "/", "Temperature"), 5000);
+
+    let mut msg = method_msg("/outputs/eDP_1", "UpdateBrightness");
+    msg.body.push_param(-1.0f64).unwrap();
+    call(&mut conn, msg);
+    assert_eq!(
+        env.get::<f64>(&mut conn, "/outputs/eDP_1", "Brightness"),
+        0.2
+    );
+}
+
+#[test]
+fn malformed_messages() {
+    const INVALID_ARGS: &str = "org.freedesktop.DBus.Error.InvalidArgs";
+
+    let mut env = TestEnv::new(&["eDP-1"]);
+    let mut conn = env.connect();
+
+    for path in ["/", "/outputs/eDP_1"] {
+        let reply = call(&mut conn, set_msg(path, "Temperature", 4000f64));
+        assert_error(&reply, INVALID_ARGS);
+        let reply = call(&mut conn, set_msg(path, "Inverted", "yes"));
+        assert_error(&reply, INVALID_ARGS);
+
+        let mut msg = method_msg(path, "UpdateTemperature");
+        msg.body.push_param("warmer").unwrap();
+        assert_error(&call(&mut conn, msg), INVALID_ARGS);
+
+        let mut msg = method_msg(path, "SetColor");
+        msg.body
+            .push_param(dict([("Brightness", f64::NAN.into())]))
+            .unwrap();
+        assert_error(&call(&mut conn, msg), INVALID_ARGS);
+
+        let mut msg = method_msg(path, "SetTemporary");
+        msg.body
+            .push_param(dict([("Gamma", f64::INFINITY.into())]))
+            .unwrap();
+        msg.body.push_param(60u32).unwrap();
+        assert_error(&call(&mut conn, msg), INVALID_ARGS);
+        let reply = call(&mut conn, set_msg(path, "Brightness", f64::NAN));
+        assert_error(&reply, INVALID_ARGS);
+    }
+
+    let mut msg = method_msg("/", "SetAppRule");
+    msg.body.push_param("movies").unwrap();
+    msg.body
+        .push_param(dict([
+            ("app_id", "mpv".into()),
+            ("brightness", f64::NAN.into()),
+        ]))
+        .unwrap();
+    assert_error(&call(&mut conn, msg), INVALID_ARGS);
+
+    let reply = call(&mut conn, method_msg("/outputs/DP_9", "ToggleInverted"));
+    assert_eq!(reply.typ, rustbus_service::rustbus::MessageType::Error);
+
+    assert!(env.daemon_alive());
+    assert_eq!(env.get::<u16>(&mut conn, "/", "Temperature"), 6500);
+    assert_eq!(env.get::<f64>(&mut conn, "/", "Brightness"), 1.0);
+    assert_eq!(env.get::<u32>(&mut conn, "/", "TemporaryRemaining"), 0);
+}
+
+#[test]
+fn gnome_night_light() {
+    const PATH: &str = "/org/gnome/SettingsDaemon/Color";
+    const GNOME_IFACE: &str = "org.gnome.SettingsDaemon.Color";
+
+    let env = TestEnv::new(&["eDP-1"]);
+    let mut conn = env.connect();
+
+    let reply = call(
+        &mut conn,
+        set_iface_msg(PATH, GNOME_IFACE, "Temperature", 4000u32),
+    );
+    assert_eq!(reply.typ, rustbus_service::rustbus::MessageType::Reply);
+    assert_eq!(env.get::<u16>(&mut conn, "/", "Temperature"), 4000);
+
+    call(
+        &mut conn,
+        set_iface_msg(PATH, GNOME_IFACE, "DisabledUntilTomorrow", true),
+    );
+    assert!(env.get::<bool>(&mut conn, "/", "Inhibited"));
+
+    call(
+        &mut conn,
+        set_iface_msg(PATH, GNOME_IFACE, "DisabledUntilTomorrow", false),
+    );
+    assert!(!env.get::<bool>(&mut conn, "/", "Inhibited"));
+}
+
+#[test]
+fn kde_night_light() {
+    const PATH: &str = "/org/kde/KWin/NightLight";
+    const KDE_IFACE: &str = "org.kde.KWin.NightLight";
+
+    let env = TestEnv::new(&["eDP-1"]);
+    let mut conn = env.connect();
+    env.set(&mut conn, "/", "Temperature", 4000u16);
+
+    let kde_msg = |method| {
+        rustbus_service::rustbus::MessageBuilder::new()
+            .call(method)
+            .on(PATH)
+            .with_interface(KDE_IFACE)
+            .at(BUS_NAME)
+            .build()
+    };
+    let reply = call(&mut conn, kde_msg("inhibit"));
+    let cookie = reply.body.parser().get::<u32>().unwrap();
+    assert!(env.get::<bool>(&mut conn, "/", "Inhibited"));
+    env.wait_for_ramp("eDP-1", |[_, _, b]| *b.last().unwrap() == u16::MAX);
+
+    let mut msg = kde_msg("uninhibit");
+    msg.body.push_param(cookie).unwrap();
+    call(&mut conn, msg);
+    assert!(!env.get::<bool>(&mut conn, "/", "Inhibited"));
+    assert_eq!(env.get::<u16>(&mut conn, "/", "Temperature"), 4000);
+}
+
+#[test]
+fn replace() {
+    use rustbus_service::rustbus::MessageType;
+
+    let mut env = TestEnv::new(&["eDP-1"]);
+    let mut conn = env.connect();
+    env.set(&mut conn, "/", "Temperature", 4000u16);
+
+    let mut old = env.start_another(&["eDP-1"], &["--replace"]);
+    wait_for(|| old.try_wait().unwrap().is_some());
+    assert!(old.wait().unwrap().success());
+    wait_for(|| call(&mut conn, get_msg("/", "Temperature")).typ == MessageType::Reply);
+    assert!(env.daemon_alive());
+    assert_eq!(env.get::<u16>(&mut conn, "/", "Temperature"), 6500);
+}
+
+#[test]
+fn terminate() {
+    let mut env = TestEnv::new(&["eDP-1"]);
+    assert!(env.terminate_daemon().success());
+}
+
+#[test]
+fn bus_name() {
+    use rustbus_service::rustbus::MessageType;
+
+    const OTHER: &str = "rs.wl-gammarelay.other";
+
+    let mut env = TestEnv::new(&["eDP-1"]);
+    let mut conn = env.connect();
+    env.spawn_another(&["DP-1"], &["--bus-name", OTHER]);
+
+    let get_other = || {
+        let mut msg = get_msg("/outputs/DP_1", "Temperature");
+        msg.dynheader.destination = Some(OTHER.to_owned());
+        msg
+    };
+    wait_for(|| call(&mut conn, get_other()).typ == MessageType::Reply);
+    assert!(env.daemon_alive());
+    assert_eq!(env.get::<u16>(&mut conn, "/", "Temperature"), 6500);
+}
+
+#[test]
+fn per_display() {
+    use std::io::{BufRead, BufReader};
+    use std::process::Stdio;
+
+    use rustbus_service::rustbus::{MessageType, message_builder::MarshalledMessage};
+
+    const WAYLAND_1: &str = "rs.wl-gammarelay.wayland_1";
+    const WAYLAND_2: &str = "rs.wl-gammarelay.wayland_2";
+
+    let mut env = TestEnv::new(&["eDP-1"]);
+    let mut conn = env.connect();
+    for (display, output) in [("wayland-1", "DP-1"), ("/run/user/1000/wayland-2", "DP-2")] {
+        let mut daemon = env.headless_command(&[output], &["--per-display"]);
+        daemon.env("WAYLAND_DISPLAY", display);
+        env.spawn(daemon);
+    }
+
+    let to = |bus_name: &str, mut msg: MarshalledMessage| {
+        msg.dynheader.destination = Some(bus_name.to_owned());
+        msg
+    };
+    let get =
+        |bus_name: &str, output: &str| to(bus_name, get_msg(&object_path(output), "Temperature"));
+    wait_for(|| call(&mut conn, get(WAYLAND_1, "DP-1")).typ == MessageType::Reply);
+    wait_for(|| call(&mut conn, get(WAYLAND_2, "DP-2")).typ == MessageType::Reply);
+    // Each instance only has the outputs of its display
+    assert_eq!(
+        call(&mut conn, get(WAYLAND_1, "DP-2")).typ,
+        MessageType::Error
+    );
+    assert_eq!(
+        call(&mut conn, get(WAYLAND_2, "DP-1")).typ,
+        MessageType::Error
+    );
+    assert!(env.daemon_alive());
+
+    let reply = call(
+        &mut conn,
+        to(WAYLAND_2, set_msg("/", "Temperature", 4000u16)),
+    );
+    assert_eq!(reply.typ, MessageType::Reply);
+
+    // `watch` follows the instance of its display
+    let mut watch = env.command();
+    watch
+        .env("WAYLAND_DISPLAY", "wayland-2")
+        .args(["--per-display", "watch", "{t}"])
+        .stdout(Stdio::piped());
+    let watch = env.spawn(watch);
+    let mut line = String::new();
+    BufReader::new(watch.stdout.take().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    assert_eq!(line.trim(), "4000");
+}
+
+#[test]
+fn read_only() {
+    const ACCESS_DENIED: &str = "org.freedesktop.DBus.Error.AccessDenied";
+
+    let env = TestEnv::with_args(&["eDP-1"], &["--read-only"]);
+    let mut conn = env.connect();
+
+    let reply = call(&mut conn, set_msg("/", "Temperature", 4000u16));
+    assert_error(&reply, ACCESS_DENIED);
+    let reply = call(&mut conn, set_msg("/outputs/eDP_1", "Brightness", 0.5));
+    assert_error(&reply, ACCESS_DENIED);
+    let reply = call(&mut conn, method_msg("/", "ToggleInverted"));
+    assert_error(&reply, ACCESS_DENIED);
+    assert_eq!(env.get::<u16>(&mut conn, "/", "Temperature"), 6500);
+    assert!(!env.get::<bool>(&mut conn, "/", "Inverted"));
+}
+
+#[test]
+fn allow_writer() {
+    // The test binary is the executable of the client
+    let exe = std::env::current_exe().unwrap();
+    let env = TestEnv::with_args(&["eDP-1"], &["--allow-writer", exe.to_str().unwrap()]);
+    let mut conn = env.connect();
+
+    env.set(&mut conn, "/", "Temperature", 4000u16);
+    assert_eq!(env.get::<u16>(&mut conn, "/", "Temperature"), 4000);
+}
+
+#[test]
+fn allow_writer_name() {
+    use rustbus_service::rustbus::{MessageType, standard_messages};
+
+    const NAME: &str = "org.example.Writer";
+
+    let env = TestEnv::with_args(&["eDP-1"], &["--allow-writer", NAME]);
+    let mut conn = env.connect();
+
+    let reply = call(&mut conn, set_msg("/", "Temperature", 4000u16));
+    assert_error(&reply, "org.freedesktop.DBus.Error.AccessDenied");
+    call(&mut conn, standard_messages::request_name(NAME, 0));
+    wait_for(|| call(&mut conn, set_msg("/", "Temperature", 4000u16)).typ == MessageType::Reply);
+    assert_eq!(env.get::<u16>(&mut conn, "/", "Temperature"), 4000);
+}
@@ -0,0 +1,133 @@
+//! Runs the X11 backend against Xvfb. The tests are skipped when Xvfb is not installed.
+
+#![cfg(feature = "x11")]
+
+mod common;
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+
+use common::*;
+use rustbus_service::rustbus::{
+    MessageBuilder, wire::ObjectPath, wire::unmarshal::traits::Variant,
+};
+use x11rb::connection::Connection;
+use x11rb::protocol::randr::{self, ConnectionExt as _};
+use x11rb::rust_connection::RustConnection;
+
+/// A virtual X server
+struct Xvfb {
+    process: Child,
+    display: String,
+}
+
+impl Xvfb {
+    /// Returns `None` if Xvfb is not installed
+    fn start() -> Option<Self> {
+        let process = Command::new("Xvfb")
+            .args(["-displayfd", "1", "-nolisten", "tcp"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut process = match process {
+            Ok(process) => process,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                eprintln!("Xvfb is not installed, skipping");
+                return None;
+            }
+            Err(e) => panic!("could not start Xvfb: {e}"),
+        };
+        // With -displayfd, the display number is written once the server is ready
+        let mut display = String::new();
+        BufReader::new(process.stdout.take().unwrap())
+            .read_line(&mut display)
+            .unwrap();
+        Some(Self {
+            process,
+            display: format!(":{}", display.trim()),
+        })
+    }
+
+    fn connect(&self) -> RustConnection {
+        x11rb::connect(Some(&self.display)).unwrap().0
+    }
+}
+
+impl Drop for Xvfb {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// Returns the ramps of the active CRTCs, which the daemon exports, as `[r, g, b]`
+fn crtc_gammas(conn: &RustConnection) -> Vec<[Vec<u16>; 3]> {
+    let root = conn.setup().roots[0].root;
+    let resources = conn
+        .randr_get_screen_resources_current(root)
+        .unwrap()
+        .reply()
+        .unwrap();
+    resources
+        .crtcs
+        .iter()
+        .filter(|&&crtc| {
+            let info = conn
+                .randr_get_crtc_info(crtc, resources.config_timestamp)
+                .unwrap()
+                .reply()
+                .unwrap();
+            info.mode != 0 && !info.outputs.is_empty()
+        })
+        .map(|&crtc| crtc_gamma(conn, crtc))
+        .collect()
+}
+
+fn crtc_gamma(conn: &RustConnection, crtc: randr::Crtc) -> [Vec<u16>; 3] {
+    let gamma = conn.randr_get_crtc_gamma(crtc).unwrap().reply().unwrap();
+    [gamma.red, gamma.green, gamma.blue]
+}
+
+#[test]
+fn crtc_gamma_is_set_and_restored() {
+    let Some(xvfb) = Xvfb::start() else {
+        return;
+    };
+    let x11 = xvfb.connect();
+    let original = crtc_gammas(&x11);
+    assert!(!original.is_empty(), "Xvfb has no active CRTC");
+    assert!(original.iter().all(|[r, _, _]| !r.is_empty()));
+
+    let mut env = TestEnv::x11(&xvfb.display);
+    let mut conn = env.connect();
+
+    // Every CRTC is exported as an output
+    let msg = MessageBuilder::new()
+        .call("GetManagedObjects")
+        .on("/")
+        .with_interface("org.freedesktop.DBus.ObjectManager")
+        .at(BUS_NAME)
+        .build();
+    let reply = call(&mut conn, msg);
+    let objects = reply
+        .body
+        .parser()
+        .get::<HashMap<ObjectPath<&str>, HashMap<&str, HashMap<&str, Variant>>>>()
+        .unwrap();
+    let outputs = objects
+        .keys()
+        .filter(|path| path.as_ref().starts_with("/outputs/"))
+        .count();
+    assert_eq!(outputs, original.len());
+
+    env.set(&mut conn, "/", "Brightness", 0.5f64);
+    wait_for(|| {
+        crtc_gammas(&x11)
+            .iter()
+            .all(|[r, _, _]| *r.last().unwrap() == u16::MAX / 2)
+    });
+
+    assert!(env.terminate_daemon().success());
+    assert_eq!(crtc_gammas(&x11), original);
+}